@@ -0,0 +1,223 @@
+//! Compares save and load time of each snapshot strategy against per-component BSON keyed by type
+//! name, which is how snapshots used to be encoded. The world also has many entities that are
+//! not rolled back, which snapshots should not pay for.
+//!
+//! A tenth of the rollback entities move between saves, so delta snapshots have something to
+//! save. Keyframes and deltas are timed separately.
+//!
+//! Run with `cargo bench --bench snapshot`.
+
+use bevy::{
+    prelude::*,
+    reflect::serde::{ReflectDeserializer, ReflectSerializer},
+    reflect::TypeRegistryArc,
+};
+use serde::de::DeserializeSeed;
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant},
+};
+
+use bevy_rbrb::{Cloned, Reflected, RollbackId, SnapshotStrategy, Snapshotter};
+
+const ENTITIES: usize = 1_000;
+const SCENERY: usize = 10_000;
+const ITERATIONS: u32 = 100;
+/// Every this many rollback entities moves between saves.
+const MOVING: usize = 10;
+
+#[derive(Reflect, Default, Clone)]
+struct Position {
+    x: f32,
+    y: f32,
+}
+
+#[derive(Reflect, Default, Clone)]
+struct Velocity(f32, f32);
+
+#[derive(Reflect, Default, Clone)]
+struct Health {
+    current: u32,
+    max: u32,
+}
+
+/// Stands in for meshes, UI and lights.
+struct Scenery(usize);
+
+struct Lit;
+
+fn world() -> World {
+    let mut world = World::new();
+    let registry = TypeRegistryArc::default();
+    {
+        let mut registry = registry.write();
+        registry.register::<f32>();
+        registry.register::<u32>();
+        registry.register::<Position>();
+        registry.register::<Velocity>();
+        registry.register::<Health>();
+    }
+    world.insert_resource(registry);
+
+    for i in 0..ENTITIES {
+        world.spawn().insert_bundle((
+            RollbackId::fixed(i as u32),
+            Position {
+                x: i as f32,
+                y: -(i as f32),
+            },
+            Velocity(1.5, -0.5),
+            Health {
+                current: i as u32,
+                max: 100,
+            },
+        ));
+    }
+    for i in 0..SCENERY {
+        let mut entity = world.spawn();
+        entity.insert_bundle((Scenery(i), Position::default()));
+        if i % 2 == 0 {
+            entity.insert(Lit);
+        }
+    }
+    world
+}
+
+fn snapshotter<S>() -> Snapshotter
+where
+    S: SnapshotStrategy<Position> + SnapshotStrategy<Velocity> + SnapshotStrategy<Health>,
+{
+    let mut snapshotter = Snapshotter::default();
+    <S as SnapshotStrategy<Position>>::register(&mut snapshotter);
+    <S as SnapshotStrategy<Velocity>>::register(&mut snapshotter);
+    <S as SnapshotStrategy<Health>>::register(&mut snapshotter);
+    snapshotter
+}
+
+fn bson_baseline(world: &mut World) -> Vec<u8> {
+    let registry = world.get_resource::<TypeRegistryArc>().unwrap().clone();
+    let registry = registry.read();
+
+    let mut entities = BTreeMap::<String, BTreeMap<String, Vec<u8>>>::new();
+    let mut query = world.query::<(&RollbackId, &Position, &Velocity, &Health)>();
+    for (id, position, velocity, health) in query.iter(world) {
+        let components = entities.entry(format!("{:?}", id)).or_default();
+        for component in [
+            position as &dyn Reflect,
+            velocity as &dyn Reflect,
+            health as &dyn Reflect,
+        ] {
+            let bson = bson::to_vec(&ReflectSerializer::new(component, &registry)).unwrap();
+            components.insert(component.type_name().to_owned(), bson);
+        }
+    }
+    bincode::serialize(&entities).unwrap()
+}
+
+/// Decodes everything, then applies it to the entities found by their `RollbackId`.
+fn bson_baseline_load(bytes: &[u8], world: &mut World) {
+    let registry = world.get_resource::<TypeRegistryArc>().unwrap().clone();
+    let registry = registry.read();
+
+    let entities: BTreeMap<String, BTreeMap<String, Vec<u8>>> =
+        bincode::deserialize(bytes).unwrap();
+    let mut decoded = Vec::new();
+    for (id, components) in entities {
+        for (name, bson) in components {
+            let document = bson::Document::from_reader(&mut &bson[..]).unwrap();
+            let value = ReflectDeserializer::new(&registry)
+                .deserialize(bson::Deserializer::new(document.into()))
+                .unwrap();
+            decoded.push((id.clone(), name, value));
+        }
+    }
+
+    let by_id: HashMap<String, Entity> = world
+        .query::<(Entity, &RollbackId)>()
+        .iter(world)
+        .map(|(entity, id)| (format!("{:?}", id), entity))
+        .collect();
+    for (id, name, value) in decoded {
+        let entity = by_id[&id];
+        if name == std::any::type_name::<Position>() {
+            apply::<Position>(world, entity, &*value);
+        } else if name == std::any::type_name::<Velocity>() {
+            apply::<Velocity>(world, entity, &*value);
+        } else if name == std::any::type_name::<Health>() {
+            apply::<Health>(world, entity, &*value);
+        }
+    }
+}
+
+fn apply<T: Reflect + Component>(world: &mut World, entity: Entity, value: &dyn Reflect) {
+    world.get_mut::<T>(entity).unwrap().apply(value);
+}
+
+fn time(mut f: impl FnMut()) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    start.elapsed() / ITERATIONS
+}
+
+/// Moves a different tenth of the rollback entities each step.
+fn step(world: &mut World, step: usize) {
+    let mut query = world.query_filtered::<&mut Position, With<RollbackId>>();
+    for (i, mut position) in query.iter_mut(world).enumerate() {
+        if i % MOVING == step % MOVING {
+            position.x += 1.;
+        }
+    }
+}
+
+fn save_and_load(name: &str, mut snapshotter: Snapshotter, world: &mut World) {
+    let cycle = Snapshotter::KEYFRAME_INTERVAL + 1;
+    let mut handle = Vec::new();
+    let (mut keyframe, mut delta) = (Duration::ZERO, Duration::ZERO);
+    let (mut keyframe_bytes, mut delta_bytes) = (0, 0);
+    for i in 0..ITERATIONS as usize * cycle {
+        step(world, i);
+        handle.clear();
+        let start = Instant::now();
+        snapshotter.save_to(&mut handle, world);
+        let elapsed = start.elapsed();
+        if i % cycle == 0 {
+            keyframe += elapsed;
+            keyframe_bytes = snapshotter.last_saved_bytes();
+        } else {
+            delta += elapsed;
+            delta_bytes = snapshotter.last_saved_bytes();
+        }
+    }
+    let keyframe = keyframe / ITERATIONS;
+    let delta = delta / (ITERATIONS * Snapshotter::KEYFRAME_INTERVAL as u32);
+    // The last save is a delta, so loading it also rebases it onto its keyframe.
+    let load = time(|| snapshotter.load_from(&handle, world));
+    println!(
+        "{:<10} keyframe save {:?}, {} bytes; delta save {:?}, {} bytes; load {:?}",
+        name, keyframe, keyframe_bytes, delta, delta_bytes, load
+    );
+}
+
+fn main() {
+    let mut world = world();
+    println!(
+        "{} rollback entities with 3 components each, {} other entities",
+        ENTITIES, SCENERY
+    );
+
+    let mut baseline = Vec::new();
+    let baseline_save = time(|| baseline = bson_baseline(&mut world));
+    let baseline_load = time(|| bson_baseline_load(&baseline, &mut world));
+    println!(
+        "{:<10} save {:?}, {} bytes; load {:?}",
+        "bson",
+        baseline_save,
+        baseline.len(),
+        baseline_load
+    );
+
+    save_and_load("reflected", snapshotter::<Reflected>(), &mut world);
+    save_and_load("cloned", snapshotter::<Cloned>(), &mut world);
+}
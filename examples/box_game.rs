@@ -19,6 +19,10 @@ struct Options {
     #[structopt(long)]
     bad_network: bool,
 
+    /// Re-simulate this many frames every frame to check for non-determinism.
+    #[structopt(long)]
+    sync_test: Option<usize>,
+
     remote_players: Vec<SocketAddr>,
 }
 
@@ -43,8 +47,8 @@ fn main() {
         builder.with_socket(basic_socket)
     };
 
-    App::build()
-        .add_plugins(DefaultPlugins)
+    let mut app = App::build();
+    app.add_plugins(DefaultPlugins)
         .add_plugin(RbrbPlugin)
         .with_session(builder.start().unwrap())
         .add_startup_system(spawn_players.system())
@@ -57,8 +61,13 @@ fn main() {
             sched
                 .add_stage("box_game", SystemStage::parallel())
                 .add_system_to_stage("box_game", move_boxes.system());
-        })
-        .run()
+        });
+
+    if let Some(check_distance) = options.sync_test {
+        app.with_sync_test(check_distance);
+    }
+
+    app.run()
 }
 
 struct Player {
@@ -105,7 +114,7 @@ fn spawn_players(
                 transform,
                 ..Default::default()
             })
-            .insert(RollbackId(format!("player/{}", id)))
+            .insert(RollbackId::fixed(id as u32).labeled(format!("player/{}", id)))
             .insert(SomethingGeneric::<u32>(42))
             .insert(Player { id });
     }
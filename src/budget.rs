@@ -0,0 +1,57 @@
+use std::time::{Duration, Instant};
+
+/// Limits how much work the rbrb stage does in one app update.
+///
+/// Without a budget, a long rollback on a slow machine stalls rendering, which makes the next
+/// update fall even further behind. Requests left over when the budget runs out are handled
+/// in the next update. A rollback is always re-simulated in full first, so the world is never
+/// left at a rolled back frame.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FrameBudget {
+    pub max_advances: Option<u32>,
+    pub max_time: Option<Duration>,
+}
+
+/// Sent for every update the rbrb stage stopped early because it ran out of [`FrameBudget`].
+#[derive(Clone, Copy, Debug)]
+pub struct FrameBudgetExceeded {
+    pub advances: u32,
+    pub elapsed: Duration,
+}
+
+pub(crate) struct BudgetTracker {
+    budget: FrameBudget,
+    started: Instant,
+    advances: u32,
+}
+
+impl BudgetTracker {
+    pub(crate) fn new(budget: FrameBudget) -> Self {
+        BudgetTracker {
+            budget,
+            started: Instant::now(),
+            advances: 0,
+        }
+    }
+
+    pub(crate) fn advanced(&mut self) {
+        self.advances += 1;
+    }
+
+    /// `Some` once the budget is used up.
+    pub(crate) fn exceeded(&self) -> Option<FrameBudgetExceeded> {
+        let elapsed = self.started.elapsed();
+        let too_many = self
+            .budget
+            .max_advances
+            .is_some_and(|max| self.advances >= max);
+        let too_long = self.budget.max_time.is_some_and(|max| elapsed >= max);
+        if !too_many && !too_long {
+            return None;
+        }
+        Some(FrameBudgetExceeded {
+            advances: self.advances,
+            elapsed,
+        })
+    }
+}
@@ -0,0 +1,156 @@
+use ::serde::*;
+use bevy_app::Events;
+use bevy_ecs::prelude::*;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// How many frames of checksums are kept around to compare against late remote checksums.
+const HISTORY: u32 = crate::ROLLBACK_WINDOW as u32;
+
+/// Only checksums of every `SEND_INTERVAL`th frame are sent to peers. The checksum is sent in
+/// the input bytes, and rbrb predicts remote input by repeating the last bytes it received, so
+/// sending a new checksum every frame would make every prediction wrong.
+const SEND_INTERVAL: u32 = 60;
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+pub struct FrameChecksum {
+    pub frame: u32,
+    pub checksum: u64,
+}
+
+/// Fired when a peer reports a different checksum for a confirmed frame than we computed.
+#[derive(Clone, Copy, Debug)]
+pub struct Desynced {
+    pub frame: u32,
+    pub local: u64,
+    pub remote: u64,
+}
+
+/// Checksums of the registered rollback state at every confirmed frame.
+#[derive(Default, Debug)]
+pub struct RbrbChecksums {
+    local: BTreeMap<u32, u64>,
+    pending_remote: BTreeMap<u32, BTreeSet<u64>>,
+    reported: BTreeSet<(u32, u64)>,
+}
+
+impl RbrbChecksums {
+    /// The checksum of the most recently confirmed frame.
+    pub fn latest(&self) -> Option<FrameChecksum> {
+        self.local
+            .iter()
+            .next_back()
+            .map(|(&frame, &checksum)| FrameChecksum { frame, checksum })
+    }
+
+    pub fn get(&self, frame: u32) -> Option<u64> {
+        self.local.get(&frame).copied()
+    }
+
+    /// The newest checksum of a frame that is a multiple of `SEND_INTERVAL`.
+    pub(crate) fn to_send(&self) -> Option<FrameChecksum> {
+        self.local
+            .iter()
+            .rev()
+            .find(|(&frame, _)| frame % SEND_INTERVAL == 0)
+            .map(|(&frame, &checksum)| FrameChecksum { frame, checksum })
+    }
+
+    fn record_local(&mut self, local: FrameChecksum) -> Vec<Desynced> {
+        self.local.insert(local.frame, local.checksum);
+
+        let remotes = self.pending_remote.remove(&local.frame).unwrap_or_default();
+        let desyncs = remotes
+            .into_iter()
+            .filter_map(|remote| self.compare(local.frame, remote))
+            .collect();
+
+        self.prune(local.frame);
+        desyncs
+    }
+
+    fn record_remote(&mut self, remote: FrameChecksum) -> Option<Desynced> {
+        if self.local.contains_key(&remote.frame) {
+            return self.compare(remote.frame, remote.checksum);
+        }
+        let too_old = self
+            .latest()
+            .is_some_and(|l| remote.frame + HISTORY < l.frame);
+        if !too_old {
+            self.pending_remote
+                .entry(remote.frame)
+                .or_default()
+                .insert(remote.checksum);
+        }
+        None
+    }
+
+    fn compare(&mut self, frame: u32, remote: u64) -> Option<Desynced> {
+        let local = *self.local.get(&frame)?;
+        if local == remote || !self.reported.insert((frame, remote)) {
+            return None;
+        }
+        Some(Desynced {
+            frame,
+            local,
+            remote,
+        })
+    }
+
+    fn prune(&mut self, latest: u32) {
+        let oldest = latest.saturating_sub(HISTORY);
+        self.local = self.local.split_off(&oldest);
+        self.pending_remote = self.pending_remote.split_off(&oldest);
+        self.reported = self.reported.split_off(&(oldest, 0));
+    }
+}
+
+/// Input as sent over the network, carrying one of our confirmed checksums along with it.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ChecksummedInput<I> {
+    pub(crate) input: I,
+    pub(crate) checksum: Option<FrameChecksum>,
+}
+
+pub(crate) fn record_local(world: &mut World, checksum: FrameChecksum) {
+    let desyncs = match world.get_resource_mut::<RbrbChecksums>() {
+        Some(mut checksums) => checksums.record_local(checksum),
+        None => return,
+    };
+    send_desyncs(world, desyncs);
+}
+
+pub(crate) fn record_remote(world: &mut World, remote: impl IntoIterator<Item = FrameChecksum>) {
+    let desyncs = match world.get_resource_mut::<RbrbChecksums>() {
+        Some(mut checksums) => remote
+            .into_iter()
+            .filter_map(|c| checksums.record_remote(c))
+            .collect(),
+        None => return,
+    };
+    send_desyncs(world, desyncs);
+}
+
+fn send_desyncs(world: &mut World, desyncs: Vec<Desynced>) {
+    for desync in desyncs {
+        log::error!(
+            "desync at frame {}: local checksum {:016x}, remote checksum {:016x}",
+            desync.frame,
+            desync.local,
+            desync.remote
+        );
+        if let Some(mut events) = world.get_resource_mut::<Events<Desynced>>() {
+            events.send(desync);
+        }
+        crate::session::send(world, crate::RbrbSessionEvent::Desynced(desync));
+    }
+}
+
+/// FNV-1a, chosen because it is stable across platforms and compiler versions.
+pub(crate) fn hash(bytes: &[u8]) -> u64 {
+    const OFFSET: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+
+    bytes
+        .iter()
+        .fold(OFFSET, |hash, &b| (hash ^ b as u64).wrapping_mul(PRIME))
+}
@@ -0,0 +1,43 @@
+use bevy_ecs::{
+    prelude::*,
+    system::{EntityCommands, SystemParam},
+};
+use bevy_transform::hierarchy::DespawnRecursiveExt;
+
+use crate::RollbackIdAllocator;
+
+/// Spawns and despawns rollback entities from systems in the rollback schedule.
+///
+/// Spawned entities get their `RollbackId` from the [`RollbackIdAllocator`], so re-simulating a
+/// frame spawns the same entities on every peer.
+///
+/// Ids are handed out in the order systems run, so every system in the rollback schedule that
+/// uses `RollbackCommands` or the allocator must be explicitly ordered against the others, e.g.
+/// with `.before()`, `.after()` or by putting them in separate stages. Otherwise two peers, or a
+/// re-simulation, can give the same entity different ids.
+#[derive(SystemParam)]
+pub struct RollbackCommands<'a> {
+    commands: Commands<'a>,
+    allocator: ResMut<'a, RollbackIdAllocator>,
+}
+
+impl<'a> RollbackCommands<'a> {
+    /// Spawns an entity with a newly allocated `RollbackId`.
+    pub fn spawn_rollback(&mut self) -> EntityCommands<'a, '_> {
+        let id = self.allocator.allocate();
+        let mut entity = self.commands.spawn();
+        entity.insert(id);
+        entity
+    }
+
+    /// Despawns a rollback entity and its children. Loading a snapshot taken before this frame
+    /// brings them back.
+    pub fn despawn_rollback(&mut self, entity: Entity) {
+        self.commands.entity(entity).despawn_recursive();
+    }
+
+    /// The underlying `Commands`, for anything that is not spawning or despawning.
+    pub fn commands(&mut self) -> &mut Commands<'a> {
+        &mut self.commands
+    }
+}
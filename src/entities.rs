@@ -0,0 +1,141 @@
+use bevy_app::Events;
+use bevy_ecs::prelude::*;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::RollbackId;
+
+/// Tracks which `Entity` currently holds each `RollbackId`.
+///
+/// Bevy cannot spawn an entity with a chosen id, so an entity restored by a rollback comes back
+/// as a different `Entity`. Code outside the rollback schedule that holds on to an `Entity` (UI,
+/// cameras, audio) can use [`RollbackEntities::resolve`] to find it again.
+#[derive(Default, Debug)]
+pub struct RollbackEntities {
+    current: BTreeMap<RollbackId, Entity>,
+    known: HashMap<Entity, Known>,
+    /// How many times entities have been tracked, i.e. snapshots saved or loaded.
+    tracked: u64,
+}
+
+#[derive(Debug)]
+struct Known {
+    id: RollbackId,
+    /// The value of `tracked` when the entity last existed.
+    last_seen: u64,
+}
+
+impl RollbackEntities {
+    /// The entity that currently has `id`, if any.
+    pub fn get(&self, id: &RollbackId) -> Option<Entity> {
+        self.current.get(id).copied()
+    }
+
+    /// The `RollbackId` of `entity`, including entities that have since been replaced by a
+    /// rollback.
+    pub fn rollback_id(&self, entity: Entity) -> Option<&RollbackId> {
+        self.known.get(&entity).map(|k| &k.id)
+    }
+
+    /// The entity that `entity` is known as after any rollbacks since it was seen. Returns `None`
+    /// if `entity` never had a `RollbackId`, or its rollback entity does not currently exist.
+    ///
+    /// Entities that stopped existing more than [`Self::KNOWN_FOR`] saves and loads ago are
+    /// forgotten.
+    pub fn resolve(&self, entity: Entity) -> Option<Entity> {
+        self.get(self.rollback_id(entity)?)
+    }
+
+    /// Twice the number of snapshots kept, as every re-simulated frame is both loaded and saved.
+    pub const KNOWN_FOR: u64 = 2 * crate::Snapshotter::CAPACITY as u64;
+
+    fn track(&mut self, current: BTreeMap<RollbackId, Entity>) {
+        self.current = current;
+        self.tracked += 1;
+        let tracked = self.tracked;
+        for (&id, &entity) in &self.current {
+            self.known.insert(
+                entity,
+                Known {
+                    id,
+                    last_seen: tracked,
+                },
+            );
+        }
+        let mut forgotten = Vec::new();
+        self.known.retain(|_, known| {
+            let keep = tracked - known.last_seen <= Self::KNOWN_FOR;
+            if !keep {
+                forgotten.push(known.id);
+            }
+            keep
+        });
+        if forgotten.is_empty() {
+            return;
+        }
+        let remembered: HashSet<RollbackId> = self.known.values().map(|k| k.id).collect();
+        for id in forgotten {
+            if !remembered.contains(&id) {
+                crate::rollback_id::forget_label(id);
+            }
+        }
+    }
+}
+
+/// A rollback entity was spawned by the rollback schedule.
+#[derive(Clone, Copy, Debug)]
+pub struct RollbackEntitySpawned {
+    pub entity: Entity,
+    pub id: RollbackId,
+}
+
+/// Loading a snapshot brought back a rollback entity that had been despawned. Only its
+/// `RollbackId` and rollback components are restored, so anything else it needs, like a mesh,
+/// has to be added again.
+#[derive(Clone, Copy, Debug)]
+pub struct RollbackEntityRestored {
+    pub entity: Entity,
+    pub id: RollbackId,
+}
+
+/// A rollback entity was despawned, either by the rollback schedule or by loading a snapshot
+/// from before it was spawned.
+#[derive(Clone, Copy, Debug)]
+pub struct RollbackEntityDespawned {
+    pub entity: Entity,
+    pub id: RollbackId,
+}
+
+/// `current` are all rollback entities in the world, and `restored` the ones spawned by loading a
+/// snapshot since the last call.
+pub(crate) fn track(world: &mut World, current: BTreeMap<RollbackId, Entity>, restored: &[Entity]) {
+    let mut entities = match world.remove_resource::<RollbackEntities>() {
+        Some(e) => e,
+        None => return,
+    };
+    let previous = std::mem::take(&mut entities.current);
+    entities.track(current);
+
+    for (&id, &entity) in &entities.current {
+        if previous.get(&id) == Some(&entity) {
+            continue;
+        }
+        if restored.contains(&entity) {
+            send(world, RollbackEntityRestored { entity, id });
+        } else {
+            send(world, RollbackEntitySpawned { entity, id });
+        }
+    }
+    for (&id, &entity) in &previous {
+        if entities.current.get(&id) != Some(&entity) {
+            send(world, RollbackEntityDespawned { entity, id });
+        }
+    }
+
+    world.insert_resource(entities);
+}
+
+fn send<T: Send + Sync + 'static>(world: &mut World, event: T) {
+    if let Some(mut events) = world.get_resource_mut::<Events<T>>() {
+        events.send(event);
+    }
+}
@@ -1,13 +1,14 @@
-use bevy_app::EventWriter;
+use bevy_app::{EventWriter, Events};
 use bevy_ecs::{
     component::Component,
-    prelude::{Local, Res},
+    prelude::{Res, ResMut, World},
     system::SystemParam,
 };
 use derive_more::*;
 use std::{
     collections::{HashMap, HashSet},
     hash::Hash,
+    time::{Duration, Instant},
 };
 
 #[derive(Default)]
@@ -21,8 +22,8 @@ pub struct NetworkEventWriter<'a, T: Component> {
     advance_confirmation: Res<'a, rbrb::Confirmation>,
     frame: Res<'a, RbrbFrame>,
 
-    // TODO(shelbyd): Don't grow memory forever.
-    sent_events: Local<'a, HashMap<u32, HashSet<T>>>,
+    sent_events: ResMut<'a, SentNetworkEvents<T>>,
+    resimulating: Option<Res<'a, crate::sync_test::Resimulating>>,
 }
 
 impl<'a, T: Send + Sync + 'static> NetworkEventWriter<'a, T> {
@@ -30,21 +31,210 @@ impl<'a, T: Send + Sync + 'static> NetworkEventWriter<'a, T> {
     where
         T: Clone + Hash + Eq,
     {
-        // TODO(shelbyd): Allowing sending multiples of the same event.
-        let sent = self.sent_events.entry(self.frame.0).or_default();
-        if !sent.contains(&event) {
-            self.unconfirmed_writer.send(Unconfirmed(event.clone()));
-            sent.insert(event.clone());
+        if self.resimulating.is_some() {
+            return;
         }
+        let confirmed = *self.advance_confirmation == rbrb::Confirmation::First;
+        let (unconfirmed, confirmed) = self.sent_events.record(event, self.frame.0, confirmed);
+        if let Some(unconfirmed) = unconfirmed {
+            self.unconfirmed_writer.send(unconfirmed);
+        }
+        if let Some(confirmed) = confirmed {
+            self.confirmed_writer.send(confirmed);
+        }
+    }
+}
+
+/// The network events sent for each frame that is not confirmed yet, so events that were
+/// predicted but not sent once the frame was confirmed can be retracted.
+pub struct SentNetworkEvents<T> {
+    frames: HashMap<u32, SentInFrame<T>>,
+    /// How many times each event has been sent so far in the frame being simulated.
+    this_frame: HashMap<T, usize>,
+    /// The newest confirmed frame.
+    confirmed_up_to: Option<u32>,
+}
+
+/// Each send is identified by the event and how many identical events were sent before it in the
+/// same frame, so identical events are kept apart, and matched up again when re-simulating.
+struct SentInFrame<T> {
+    /// When each send was first predicted.
+    predicted: HashMap<(T, usize), Instant>,
+    confirmed: HashSet<(T, usize)>,
+}
+
+impl<T> Default for SentNetworkEvents<T> {
+    fn default() -> Self {
+        SentNetworkEvents {
+            frames: HashMap::new(),
+            this_frame: HashMap::new(),
+            confirmed_up_to: None,
+        }
+    }
+}
 
-        if *self.advance_confirmation == rbrb::Confirmation::First {
-            self.confirmed_writer.send(Confirmed(event));
+impl<T: Clone + Hash + Eq> SentNetworkEvents<T> {
+    /// Records `event` being sent by `frame`, returning the events to send for it: `Unconfirmed`
+    /// the first time it is predicted, and `Confirmed` if `confirmed`.
+    fn record(
+        &mut self,
+        event: T,
+        frame: u32,
+        confirmed: bool,
+    ) -> (Option<Unconfirmed<T>>, Option<Confirmed<T>>) {
+        // Confirmed frames are settled, and would never be forgotten again.
+        if self.confirmed_up_to.is_some_and(|c| frame <= c) {
+            return (None, None);
+        }
+        let count = self.this_frame.entry(event.clone()).or_default();
+        let send = (event.clone(), *count);
+        *count += 1;
+
+        let sent = self.frames.entry(frame).or_default();
+        let mut unconfirmed = None;
+        let predicted_at = *sent.predicted.entry(send.clone()).or_insert_with(|| {
+            unconfirmed = Some(Unconfirmed {
+                event: event.clone(),
+                frame,
+            });
+            Instant::now()
+        });
+
+        if !confirmed {
+            return (unconfirmed, None);
+        }
+        sent.confirmed.insert(send);
+        let confirmed = Confirmed {
+            event,
+            frame,
+            latency: predicted_at.elapsed(),
+        };
+        (unconfirmed, Some(confirmed))
+    }
+
+    /// Forgets `frame`, returning the events it was predicted to send but did not once confirmed.
+    fn confirm(&mut self, frame: u32) -> Vec<Retracted<T>> {
+        self.confirmed_up_to = self.confirmed_up_to.max(Some(frame));
+        let sent = match self.frames.remove(&frame) {
+            Some(sent) => sent,
+            None => return Vec::new(),
+        };
+        sent.predicted
+            .into_keys()
+            .filter(|send| !sent.confirmed.contains(send))
+            .map(|(event, _)| Retracted { event, frame })
+            .collect()
+    }
+}
+
+impl<T> Default for SentInFrame<T> {
+    fn default() -> Self {
+        SentInFrame {
+            predicted: HashMap::new(),
+            confirmed: HashSet::new(),
         }
     }
 }
 
+/// Called once `frame` has been simulated with `Confirmation::First`. The frame will not be
+/// simulated again, so it is forgotten.
+pub(crate) fn retract_unconfirmed<T: Component + Clone + Hash + Eq>(world: &mut World, frame: u32) {
+    let retracted = match world.get_resource_mut::<SentNetworkEvents<T>>() {
+        Some(mut sent) => sent.confirm(frame),
+        None => return,
+    };
+    if let Some(mut events) = world.get_resource_mut::<Events<Retracted<T>>>() {
+        for retracted in retracted {
+            events.send(retracted);
+        }
+    }
+}
+
+pub(crate) fn begin_network_event_frame<T: Component>(world: &mut World) {
+    if let Some(mut sent) = world.get_resource_mut::<SentNetworkEvents<T>>() {
+        sent.this_frame.clear();
+    }
+}
+
+pub(crate) fn reset_network_events<T: Component>(world: &mut World) {
+    world.insert_resource(SentNetworkEvents::<T>::default());
+}
+
 #[derive(Deref, DerefMut, Clone, Copy, Debug)]
-pub struct Confirmed<T>(pub T);
+pub struct Confirmed<T> {
+    #[deref]
+    #[deref_mut]
+    pub event: T,
+    /// The frame that sent the event.
+    pub frame: u32,
+    /// How long after the event was first predicted it was confirmed. Zero if it was not
+    /// predicted.
+    pub latency: Duration,
+}
+
+#[derive(Deref, DerefMut, Clone, Copy, Debug)]
+pub struct Unconfirmed<T> {
+    #[deref]
+    #[deref_mut]
+    pub event: T,
+    /// The frame that sent the event.
+    pub frame: u32,
+}
 
+/// An [`Unconfirmed`] event that turned out not to happen once its frame was confirmed.
 #[derive(Deref, DerefMut, Clone, Copy, Debug)]
-pub struct Unconfirmed<T>(pub T);
+pub struct Retracted<T> {
+    #[deref]
+    #[deref_mut]
+    pub event: T,
+    /// The frame that was predicted to send the event.
+    pub frame: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, PartialEq, Eq, Hash, Debug)]
+    struct Hit(u32);
+
+    /// Predicts every frame twice as many hits as it turns out to send once confirmed.
+    fn simulate(sent: &mut SentNetworkEvents<Hit>, frame: u32, confirmed: bool) -> usize {
+        sent.this_frame.clear();
+        let hits = if confirmed { 1 } else { 2 };
+        (0..hits)
+            .filter(|_| sent.record(Hit(frame % 3), frame, confirmed).0.is_some())
+            .count()
+    }
+
+    #[test]
+    fn sent_events_stay_bounded_over_a_long_session() {
+        const PREDICTED: u32 = 8;
+        let mut sent = SentNetworkEvents::<Hit>::default();
+
+        // An hour at 60 frames a second.
+        for frame in 0..60 * 60 * 60 {
+            assert_eq!(simulate(&mut sent, frame, false), 2);
+            let confirmed = match frame.checked_sub(PREDICTED) {
+                Some(c) => c,
+                None => continue,
+            };
+
+            // Roll back to the newly confirmed frame and predict the rest again.
+            assert_eq!(simulate(&mut sent, confirmed, true), 0);
+            for predicted in confirmed + 1..=frame {
+                assert_eq!(simulate(&mut sent, predicted, false), 0);
+            }
+            let retracted = sent.confirm(confirmed);
+            assert_eq!(retracted.len(), 1);
+            assert_eq!(retracted[0].event, Hit(confirmed % 3));
+            assert_eq!(retracted[0].frame, confirmed);
+
+            // Sync tests re-simulate frames that are already confirmed.
+            simulate(&mut sent, confirmed.saturating_sub(1), true);
+
+            assert!(sent.frames.len() <= PREDICTED as usize);
+            assert!(sent.this_frame.len() <= 1);
+        }
+    }
+}
@@ -1,26 +1,67 @@
 use ::serde::*;
 use bevy_app::*;
-use bevy_ecs::{prelude::*, system::ExclusiveSystem};
+use bevy_ecs::{component::Component, prelude::*, system::ExclusiveSystem};
 use std::time::Duration;
 
-// Internal TODO:
-//   - Allow serializing non SerDe types with a serializer (notably Transform).
-//   - Keep entity ids the same across rollbacks.
-
 pub use rbrb::*;
 
+mod budget;
+pub use budget::{FrameBudget, FrameBudgetExceeded};
+mod checksum;
+pub use checksum::{Desynced, FrameChecksum, RbrbChecksums};
+mod commands;
+pub use commands::RollbackCommands;
+mod entities;
+pub use entities::{
+    RollbackEntities, RollbackEntityDespawned, RollbackEntityRestored, RollbackEntitySpawned,
+};
 mod event;
-pub use event::{Confirmed, NetworkEventWriter, Unconfirmed};
+pub use event::{Confirmed, NetworkEventWriter, Retracted, Unconfirmed};
+mod rollback_event;
+pub use rollback_event::{RollbackEventReader, RollbackEventWriter};
+mod rollback_id;
+pub use rollback_id::{RollbackId, RollbackIdAllocator};
+mod session;
+pub use session::{run_if_rbrb_running, RbrbCommandsExt, RbrbSessionEvent, RbrbState};
 mod snapshot;
-pub use snapshot::{RegisterComponent, RegisterResource};
+pub use snapshot::{
+    BincodeCodec, Cloned, ClonedEq, CodecError, Reflected, RegisterComponent,
+    RegisterMappedComponent, RegisterResource, ResyncRequired, RollbackCodec, SnapshotError,
+    SnapshotErrorPolicy, SnapshotStrategy, Snapshotter,
+};
 mod stage;
+pub use stage::RbrbRequestHandler;
 use stage::*;
+mod stats;
+pub use stats::{PlayerNetworkStats, RbrbNetworkStats};
+mod sync_test;
+pub use sync_test::SyncTestMismatch;
+
+/// How many frames of history are kept, comfortably more frames than rbrb will roll back.
+/// Snapshots, rollback events and checksums are all kept for this many frames.
+pub(crate) const ROLLBACK_WINDOW: usize = 256;
 
 pub struct RbrbPlugin;
 
 impl Plugin for RbrbPlugin {
     fn build(&self, app: &mut AppBuilder) {
-        app.add_stage_before(CoreStage::Update, "rbrb_update", RbrbStage::new());
+        app.add_stage_before(CoreStage::Update, "rbrb_update", RbrbStage::new())
+            .init_resource::<RbrbChecksums>()
+            .init_resource::<RbrbNetworkStats>()
+            .init_resource::<RbrbState>()
+            .init_resource::<RollbackEntities>()
+            .add_event::<RollbackEntitySpawned>()
+            .add_event::<RollbackEntityRestored>()
+            .add_event::<RollbackEntityDespawned>()
+            .add_event::<Desynced>()
+            .add_event::<RbrbSessionEvent>()
+            .add_event::<FrameBudgetExceeded>()
+            .add_event::<SyncTestMismatch>()
+            .init_resource::<SnapshotErrorPolicy>()
+            .add_event::<SnapshotError>()
+            .add_event::<ResyncRequired>()
+            .init_resource::<RollbackIdAllocator>()
+            .add_rollback_resource::<RollbackIdAllocator>();
     }
 }
 
@@ -28,12 +69,10 @@ pub struct RbrbTime {
     pub delta: Duration,
 }
 
-#[derive(Default, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize, Clone, Debug)]
-#[serde(transparent)]
-pub struct RollbackId(pub String);
-
 pub trait RbrbAppExt {
     fn with_session(&mut self, session: rbrb::Session) -> &mut Self;
+    /// The session's default input must be set with
+    /// [`typed_default_inputs`](SessionBuilderExt::typed_default_inputs).
     fn with_typed_input_system<
         I: serde::Serialize + serde::de::DeserializeOwned + Send + Sync + 'static,
         S: System<In = (), Out = I>,
@@ -43,11 +82,40 @@ pub trait RbrbAppExt {
         system: impl IntoSystem<Params, S>,
     ) -> &mut Self;
 
+    /// Every frame, load the state from `check_distance` frames ago and re-simulate to check
+    /// that the rollback schedule is deterministic. Intended for sessions with a single local
+    /// player and no network.
+    fn with_sync_test(&mut self, check_distance: usize) -> &mut Self;
+    fn with_frame_budget(&mut self, budget: FrameBudget) -> &mut Self;
+
     fn update_rollback_schedule(&mut self, f: impl FnOnce(&mut Schedule)) -> &mut Self;
     fn add_rollback_component<T: RegisterComponent>(&mut self) -> &mut Self;
+    /// Roll back a component saved with strategy `S`, e.g. [`Cloned`] to skip serialization.
+    fn add_rollback_component_as<T, S: SnapshotStrategy<T>>(&mut self) -> &mut Self;
+    /// Like [`add_rollback_component`](RbrbAppExt::add_rollback_component), for components that
+    /// refer to other entities, e.g. `Parent` and `Children`. References to entities without a
+    /// `RollbackId` are kept as they are.
+    fn add_rollback_component_mapped<T: RegisterMappedComponent>(&mut self) -> &mut Self;
+    /// Roll back a component that does not implement `Reflect`, using its serde implementation.
+    fn add_rollback_component_serde<T: Component + serde::Serialize + serde::de::DeserializeOwned>(
+        &mut self,
+    ) -> &mut Self;
+    /// Roll back a component that does not implement `Reflect`, encoded by `S`.
+    fn add_rollback_component_with<T: Component, S: RollbackCodec<T>>(&mut self) -> &mut Self;
     fn add_rollback_resource<T: RegisterResource>(&mut self) -> &mut Self;
 
-    fn add_network_event<T: Send + Sync + 'static>(&mut self) -> &mut Self;
+    fn add_network_event<T: Component + Clone + std::hash::Hash + Eq>(&mut self) -> &mut Self;
+    /// Add an event that is sent with [`RollbackEventWriter`] and read with
+    /// [`RollbackEventReader`] inside the rollback schedule.
+    ///
+    /// The events of every frame that may be re-simulated are kept, so a rollback restores the
+    /// events readers saw, and events from a discarded prediction are never seen again inside the
+    /// rollback schedule. Once a frame is confirmed, its events are also sent as `Events<T>` for
+    /// systems outside the rollback schedule, so those never see events from a wrong prediction.
+    fn add_rollback_event<T: Component + Clone>(&mut self) -> &mut Self;
+    /// Add a handler for rbrb requests the plugin does not handle itself. Handlers are tried in
+    /// the order they were added; requests no handler takes are logged once per variant.
+    fn add_request_handler(&mut self, handler: impl RbrbRequestHandler) -> &mut Self;
 }
 
 impl RbrbAppExt for AppBuilder {
@@ -77,13 +145,49 @@ impl RbrbAppExt for AppBuilder {
         self
     }
 
+    fn with_sync_test(&mut self, check_distance: usize) -> &mut Self {
+        get_rbrb_stage(self).sync_test = Some(sync_test::SyncTest::new(check_distance));
+        self
+    }
+
+    fn with_frame_budget(&mut self, budget: FrameBudget) -> &mut Self {
+        get_rbrb_stage(self).frame_budget = budget;
+        self
+    }
+
     fn update_rollback_schedule(&mut self, f: impl FnOnce(&mut Schedule)) -> &mut Self {
         f(&mut get_rbrb_stage(self).schedule);
         self
     }
 
     fn add_rollback_component<T: RegisterComponent>(&mut self) -> &mut Self {
-        get_rbrb_stage(self).snapshotter.register_component::<T>();
+        self.add_rollback_component_as::<T, Reflected>()
+    }
+
+    fn add_rollback_component_as<T, S: SnapshotStrategy<T>>(&mut self) -> &mut Self {
+        S::register(&mut get_rbrb_stage(self).snapshotter);
+        self
+    }
+
+    fn add_rollback_component_mapped<T: RegisterMappedComponent>(&mut self) -> &mut Self {
+        get_rbrb_stage(self)
+            .snapshotter
+            .register_entity_references::<T>();
+        self
+    }
+
+    fn add_rollback_component_serde<
+        T: Component + serde::Serialize + serde::de::DeserializeOwned,
+    >(
+        &mut self,
+    ) -> &mut Self {
+        self.add_rollback_component_with::<T, BincodeCodec>()
+    }
+
+    fn add_rollback_component_with<T: Component, S: RollbackCodec<T>>(&mut self) -> &mut Self {
+        get_rbrb_stage(self)
+            .snapshotter
+            .register_component_with::<T, S>();
         self
     }
 
@@ -92,9 +196,42 @@ impl RbrbAppExt for AppBuilder {
         self
     }
 
-    fn add_network_event<T: Send + Sync + 'static>(&mut self) -> &mut Self {
+    fn add_network_event<T: Component + Clone + std::hash::Hash + Eq>(&mut self) -> &mut Self {
         self.add_event::<event::Confirmed<T>>();
         self.add_event::<event::Unconfirmed<T>>();
+        self.add_event::<event::Retracted<T>>();
+        self.init_resource::<event::SentNetworkEvents<T>>();
+        let stage = get_rbrb_stage(self);
+        stage
+            .before_advance
+            .push(event::begin_network_event_frame::<T>);
+        stage
+            .confirm_network_events
+            .push(event::retract_unconfirmed::<T>);
+        stage.reset_session.push(event::reset_network_events::<T>);
+        self
+    }
+
+    fn add_rollback_event<T: Component + Clone>(&mut self) -> &mut Self {
+        self.add_event::<T>();
+        self.init_resource::<rollback_event::RollbackEvents<T>>();
+        let stage = get_rbrb_stage(self);
+        stage
+            .before_advance
+            .push(rollback_event::begin_rollback_event_frame::<T>);
+        stage
+            .after_advance
+            .push(rollback_event::publish_rollback_events::<T>);
+        stage
+            .reset_session
+            .push(rollback_event::reset_rollback_events::<T>);
+        self
+    }
+
+    fn add_request_handler(&mut self, handler: impl RbrbRequestHandler) -> &mut Self {
+        get_rbrb_stage(self)
+            .request_handlers
+            .push(Box::new(handler));
         self
     }
 }
@@ -107,26 +244,47 @@ fn get_rbrb_stage(builder: &mut AppBuilder) -> &mut RbrbStage {
         .expect("could not find RbrbStage, install RbrbPlugin")
 }
 
-fn serialize_inputs<I: serde::Serialize>(input: In<I>) -> Vec<u8> {
-    bincode::serialize(&input.0).unwrap()
+fn serialize_inputs<I: serde::Serialize>(
+    input: In<I>,
+    checksums: Option<Res<RbrbChecksums>>,
+) -> Vec<u8> {
+    let input = checksum::ChecksummedInput {
+        input: input.0,
+        checksum: checksums.and_then(|c| c.to_send()),
+    };
+    bincode::serialize(&input).unwrap()
 }
 
 fn parse_inputs<I: serde::de::DeserializeOwned + Send + Sync + 'static>(world: &mut World) {
     let player_inputs = world
         .get_resource::<PlayerInputs>()
         .expect("should have specified PlayerInputs");
-    let parsed_inputs = player_inputs
-        .clone()
-        .deep_map(|i| bincode::deserialize::<I>(&i).unwrap());
+    let remote_checksums = std::cell::RefCell::new(Vec::new());
+    let parsed_inputs = player_inputs.clone().deep_map(|i| {
+        let parsed = bincode::deserialize::<checksum::ChecksummedInput<I>>(&i)
+            .expect("inputs should be sent by a typed input system, see typed_default_inputs");
+        remote_checksums.borrow_mut().extend(parsed.checksum);
+        parsed.input
+    });
     world.insert_resource(parsed_inputs);
+    checksum::record_remote(world, remote_checksums.into_inner());
 }
 
 pub trait SessionBuilderExt {
+    /// Sets the default input for sessions using
+    /// [`with_typed_input_system`](RbrbAppExt::with_typed_input_system).
+    ///
+    /// Inputs are sent with a checksum attached, so raw bytes passed to `default_inputs` directly
+    /// will not parse, and the rbrb stage panics when it reads them.
     fn typed_default_inputs<I: Serialize>(self, i: I) -> Self;
 }
 
 impl SessionBuilderExt for SessionBuilder {
     fn typed_default_inputs<I: Serialize>(self, i: I) -> Self {
-        self.default_inputs(bincode::serialize(&i).unwrap())
+        let input = checksum::ChecksummedInput {
+            input: i,
+            checksum: None,
+        };
+        self.default_inputs(bincode::serialize(&input).unwrap())
     }
 }
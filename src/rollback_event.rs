@@ -0,0 +1,185 @@
+use bevy_app::Events;
+use bevy_ecs::{
+    component::Component,
+    prelude::{Local, Res, ResMut, World},
+    system::SystemParam,
+};
+use rbrb::Confirmation;
+use std::collections::VecDeque;
+
+use crate::event::RbrbFrame;
+
+/// The events sent by each recently simulated frame.
+///
+/// This plays the part of `Events<T>`'s double buffer inside the rollback schedule. Every frame
+/// that may still be re-simulated keeps its own events, so rolling back to a frame restores the
+/// events of the frame before it, and re-simulating a frame replaces the events it sent before.
+pub struct RollbackEvents<T> {
+    frames: VecDeque<SentInFrame<T>>,
+    /// Tells apart simulations of the same frame, so readers can tell a re-simulation from
+    /// reading twice in one frame.
+    next_run: u64,
+    /// The newest confirmed frame whose events have been passed on to `Events<T>`.
+    published: Option<u32>,
+}
+
+struct SentInFrame<T> {
+    frame: u32,
+    run: u64,
+    events: Vec<T>,
+}
+
+impl<T> Default for RollbackEvents<T> {
+    fn default() -> Self {
+        RollbackEvents {
+            frames: VecDeque::new(),
+            next_run: 0,
+            published: None,
+        }
+    }
+}
+
+impl<T> RollbackEvents<T> {
+    fn begin_frame(&mut self, frame: u32) {
+        // Everything from `frame` on was simulated from a state that has since been rolled back.
+        while self.frames.back().is_some_and(|f| f.frame >= frame) {
+            self.frames.pop_back();
+        }
+        if self.frames.len() == crate::ROLLBACK_WINDOW {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(SentInFrame {
+            frame,
+            run: self.next_run,
+            events: Vec::new(),
+        });
+        self.next_run += 1;
+    }
+
+    fn get(&self, frame: u32) -> Option<&SentInFrame<T>> {
+        self.frames.iter().rev().find(|f| f.frame == frame)
+    }
+}
+
+/// Sends events to [`RollbackEventReader`]s, from systems in the rollback schedule.
+#[derive(SystemParam)]
+pub struct RollbackEventWriter<'a, T: Component> {
+    events: ResMut<'a, RollbackEvents<T>>,
+}
+
+impl<'a, T: Component> RollbackEventWriter<'a, T> {
+    pub fn send(&mut self, event: T) {
+        self.events
+            .frames
+            .back_mut()
+            .expect("rollback events are sent from the rollback schedule")
+            .events
+            .push(event);
+    }
+}
+
+/// Reads events sent with a [`RollbackEventWriter`], from systems in the rollback schedule.
+///
+/// Like `EventReader`, a reader sees every event sent since it last ran, in this frame or late in
+/// the previous one. Where it got to in each frame is remembered, so after a rollback it sees the
+/// same events it saw when the frame was first simulated.
+#[derive(SystemParam)]
+pub struct RollbackEventReader<'a, T: Component> {
+    events: Res<'a, RollbackEvents<T>>,
+    frame: Res<'a, RbrbFrame>,
+    cursor: Local<'a, RollbackEventCursor>,
+}
+
+/// For each frame a reader ran in: the frame, which simulation of it, and how many of its events
+/// had been read.
+#[derive(Default)]
+pub struct RollbackEventCursor {
+    read: VecDeque<(u32, u64, usize)>,
+}
+
+impl<'a, T: Component> RollbackEventReader<'a, T> {
+    pub fn iter(&mut self) -> impl DoubleEndedIterator<Item = &T> {
+        let frame = self.frame.0;
+        let current = self.events.get(frame);
+        let previous = frame.checked_sub(1).and_then(|f| self.events.get(f));
+        let cursor = &mut self.cursor.read;
+
+        let (previous_start, current_start) = match read_in(cursor, current) {
+            // Read before in this same simulation of the frame.
+            Some(read) => (usize::MAX, read),
+            None => (read_in(cursor, previous).unwrap_or(0), 0),
+        };
+
+        // Anything from this frame on is either from a discarded simulation or replaced here.
+        while cursor.back().is_some_and(|&(f, _, _)| f >= frame) {
+            cursor.pop_back();
+        }
+        if cursor.len() == crate::ROLLBACK_WINDOW {
+            cursor.pop_front();
+        }
+        if let Some(current) = current {
+            cursor.push_back((frame, current.run, current.events.len()));
+        }
+
+        let previous = previous.map_or(&[][..], |p| &p.events[..]);
+        let current = current.map_or(&[][..], |c| &c.events[..]);
+        previous
+            .get(previous_start..)
+            .unwrap_or(&[])
+            .iter()
+            .chain(current.get(current_start..).unwrap_or(&[]))
+    }
+}
+
+/// How many of `sent`'s events were read, if this reader ran in that simulation of its frame.
+fn read_in<T>(
+    cursor: &VecDeque<(u32, u64, usize)>,
+    sent: Option<&SentInFrame<T>>,
+) -> Option<usize> {
+    let sent = sent?;
+    cursor
+        .iter()
+        .rev()
+        .find(|&&(frame, run, _)| frame == sent.frame && run == sent.run)
+        .map(|&(_, _, read)| read)
+}
+
+pub(crate) fn begin_rollback_event_frame<T: Component>(world: &mut World) {
+    let frame = match world.get_resource::<RbrbFrame>() {
+        Some(f) => f.0,
+        None => return,
+    };
+    if let Some(mut events) = world.get_resource_mut::<RollbackEvents<T>>() {
+        events.begin_frame(frame);
+    }
+}
+
+/// Passes the events of a newly confirmed frame on to `Events<T>`, for systems outside the
+/// rollback schedule. Predicted frames are left out, as their events may not survive a rollback.
+pub(crate) fn publish_rollback_events<T: Component + Clone>(world: &mut World) {
+    if world.get_resource::<Confirmation>() != Some(&Confirmation::First) {
+        return;
+    }
+    let published = match world.get_resource_mut::<RollbackEvents<T>>() {
+        Some(mut events) => {
+            let (frame, sent) = match events.frames.back() {
+                Some(sent) if events.published.is_none_or(|p| sent.frame > p) => {
+                    (sent.frame, sent.events.clone())
+                }
+                _ => return,
+            };
+            events.published = Some(frame);
+            sent
+        }
+        None => return,
+    };
+    if let Some(mut events) = world.get_resource_mut::<Events<T>>() {
+        for event in published {
+            events.send(event);
+        }
+    }
+}
+
+pub(crate) fn reset_rollback_events<T: Component>(world: &mut World) {
+    world.insert_resource(RollbackEvents::<T>::default());
+}
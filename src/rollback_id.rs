@@ -0,0 +1,92 @@
+use ::serde::*;
+use bevy_reflect::Reflect;
+use std::fmt;
+
+/// Identifies an entity across rollbacks and peers.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize, Clone, Copy)]
+#[serde(transparent)]
+pub struct RollbackId(u64);
+
+impl RollbackId {
+    /// An id for an entity every peer spawns the same way, e.g. one per player. These never
+    /// collide with ids from a [`RollbackIdAllocator`].
+    pub const fn fixed(id: u32) -> Self {
+        RollbackId(id as u64)
+    }
+
+    /// Attaches a label that is shown when debug-formatting this id. Labels are only kept in
+    /// debug builds.
+    pub fn labeled(self, label: impl Into<String>) -> Self {
+        #[cfg(debug_assertions)]
+        labels::insert(self, label.into());
+        #[cfg(not(debug_assertions))]
+        let _ = label;
+        self
+    }
+}
+
+/// Drops the label of an id whose entity is gone for good, so labels don't pile up over a long
+/// session.
+pub(crate) fn forget_label(id: RollbackId) {
+    #[cfg(debug_assertions)]
+    labels::remove(id);
+    #[cfg(not(debug_assertions))]
+    let _ = id;
+}
+
+impl fmt::Debug for RollbackId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        #[cfg(debug_assertions)]
+        if let Some(label) = labels::get(*self) {
+            return write!(f, "RollbackId({} {:?})", self.0, label);
+        }
+        write!(f, "RollbackId({})", self.0)
+    }
+}
+
+#[cfg(debug_assertions)]
+mod labels {
+    use std::{collections::BTreeMap, sync::Mutex};
+
+    use super::RollbackId;
+
+    static LABELS: Mutex<BTreeMap<RollbackId, String>> = Mutex::new(BTreeMap::new());
+
+    pub(super) fn insert(id: RollbackId, label: String) {
+        LABELS.lock().unwrap().insert(id, label);
+    }
+
+    pub(super) fn remove(id: RollbackId) {
+        LABELS.lock().unwrap().remove(&id);
+    }
+
+    pub(super) fn get(id: RollbackId) -> Option<String> {
+        LABELS.lock().unwrap().get(&id).cloned()
+    }
+}
+
+/// Hands out `RollbackId`s to entities spawned by the rollback schedule.
+///
+/// The allocator is rolled back with everything else, so a re-simulated frame hands out the same
+/// ids it did the first time, on every peer.
+#[derive(Reflect, Debug)]
+pub struct RollbackIdAllocator {
+    next: u64,
+}
+
+impl RollbackIdAllocator {
+    /// Leaves room below for [`RollbackId::fixed`].
+    const FIRST: u64 = 1 << 32;
+
+    pub fn allocate(&mut self) -> RollbackId {
+        let id = RollbackId(self.next);
+        self.next += 1;
+        id
+    }
+}
+
+impl Default for RollbackIdAllocator {
+    fn default() -> Self {
+        RollbackIdAllocator { next: Self::FIRST }
+    }
+}
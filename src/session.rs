@@ -0,0 +1,120 @@
+use bevy_app::Events;
+use bevy_ecs::{prelude::*, schedule::ShouldRun, system::Command};
+use bevy_transform::hierarchy::despawn_with_children_recursive;
+use rbrb::Session;
+
+use crate::{Desynced, RollbackId};
+
+/// Changes in the state of the rbrb `Session`, for lobbies and HUDs.
+///
+/// `RbrbPlugin` sends `Synchronizing` with no progress when a session is first seen, `Running`
+/// once it starts advancing frames and `Desynced` alongside every [`Desynced`] event.
+///
+/// rbrb's `Session` does not report peer connections or synchronization progress, so the plugin
+/// never sends `PeerConnected` or `PeerDisconnected`, or `Synchronizing` with any other progress.
+/// App code that can observe them, e.g. an [`RbrbRequestHandler`](crate::RbrbRequestHandler) or
+/// the app's own socket, sends them as `Events<RbrbSessionEvent>` for the lobby and HUD.
+#[derive(Clone, Debug)]
+pub enum RbrbSessionEvent {
+    PeerConnected {
+        player: usize,
+    },
+    PeerDisconnected {
+        player: usize,
+    },
+    /// `progress` is between 0 and 1.
+    Synchronizing {
+        progress: f32,
+    },
+    Running,
+    Desynced(Desynced),
+}
+
+pub(crate) fn send(world: &mut World, event: RbrbSessionEvent) {
+    if let Some(mut events) = world.get_resource_mut::<Events<RbrbSessionEvent>>() {
+        events.send(event);
+    }
+}
+
+/// Whether a match is running. Kept up to date by the rbrb stage, so systems outside the
+/// rollback schedule can gate on it, e.g. with [`run_if_rbrb_running`].
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RbrbState {
+    #[default]
+    NoSession,
+    Synchronizing,
+    Running,
+}
+
+pub fn run_if_rbrb_running(state: Res<RbrbState>) -> ShouldRun {
+    match *state {
+        RbrbState::Running => ShouldRun::Yes,
+        _ => ShouldRun::No,
+    }
+}
+
+/// Bumped every time a session is started or ended at runtime, so the stage drops what it kept
+/// from the previous session even if it never ran while there was no session.
+#[derive(Default)]
+pub(crate) struct SessionGeneration(pub(crate) u64);
+
+/// Starting, ending and replacing sessions from a running app.
+pub trait RbrbCommandsExt {
+    /// Replaces any current session. Everything kept for the previous session is reset, but
+    /// rollback entities are left as they are.
+    fn start_rbrb_session(&mut self, session: Session) -> &mut Self;
+    /// Ends the current session and resets the rollback state it built up. Rollback entities
+    /// are despawned if `despawn_rollback_entities` is set, otherwise they are left as they are.
+    fn end_rbrb_session(&mut self, despawn_rollback_entities: bool) -> &mut Self;
+}
+
+impl RbrbCommandsExt for Commands<'_> {
+    fn start_rbrb_session(&mut self, session: Session) -> &mut Self {
+        self.add(StartSession(session));
+        self
+    }
+
+    fn end_rbrb_session(&mut self, despawn_rollback_entities: bool) -> &mut Self {
+        self.add(EndSession {
+            despawn_rollback_entities,
+        });
+        self
+    }
+}
+
+struct StartSession(Session);
+
+impl Command for StartSession {
+    fn write(self: Box<Self>, world: &mut World) {
+        next_generation(world);
+        world.insert_resource(self.0);
+    }
+}
+
+struct EndSession {
+    despawn_rollback_entities: bool,
+}
+
+impl Command for EndSession {
+    fn write(self: Box<Self>, world: &mut World) {
+        next_generation(world);
+        world.remove_resource::<Session>();
+
+        if self.despawn_rollback_entities {
+            let entities: Vec<Entity> = world
+                .query_filtered::<Entity, With<RollbackId>>()
+                .iter(world)
+                .collect();
+            for entity in entities {
+                despawn_with_children_recursive(world, entity);
+            }
+        }
+    }
+}
+
+/// The rbrb stage resets everything it kept for the session the next time it runs.
+pub(crate) fn next_generation(world: &mut World) {
+    world
+        .get_resource_or_insert_with(SessionGeneration::default)
+        .0 += 1;
+}
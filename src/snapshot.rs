@@ -1,122 +1,585 @@
-use ::serde::{de::DeserializeSeed, *};
-use bevy_ecs::{component::Component, prelude::*};
-use bevy_reflect::{
-    serde::{ReflectDeserializer, ReflectSerializer},
-    *,
+use ::serde::*;
+use bevy_ecs::{
+    component::Component,
+    entity::{EntityMap, MapEntities, MapEntitiesError},
+    prelude::*,
+    world::FromWorld,
+};
+use bevy_reflect::*;
+use bevy_transform::hierarchy::despawn_with_children_recursive;
+use bincode::Options;
+use std::{
+    any::TypeId,
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
 };
-use std::collections::BTreeMap;
 
 use crate::RollbackId;
 
+mod change_ticks;
+use change_ticks::ReflectChangeTicks;
+mod codec;
+use codec::CodecComponent;
+pub use codec::{BincodeCodec, CodecError, RollbackCodec};
+mod error;
+pub(crate) use error::ResyncPending;
+use error::{Aborted, ErrorSink};
+pub use error::{ResyncRequired, SnapshotError, SnapshotErrorPolicy};
+mod map_entities;
+use map_entities::ReflectSnapshotEntities;
+mod query;
+use query::{RollbackEntity, RollbackQuery};
+mod reflect_codec;
+use reflect_codec::ReflectTemplate;
 mod reflect_component;
 use reflect_component::ReflectComponent;
 mod reflect_resource;
 use reflect_resource::ReflectResource;
+mod strategy;
+pub use strategy::{Cloned, ClonedEq, Reflected, SnapshotStrategy};
+use strategy::{ClonedComponent, ClonedValue};
 
+/// Saves and restores the registered rollback components and resources of a `World`.
 #[derive(Default)]
 pub struct Snapshotter {
     component_registry: TypeRegistry,
+    codec_components: Vec<(ComponentKey, CodecComponent)>,
+    cloned_components: Vec<(ComponentKey, ClonedComponent)>,
     resource_registry: TypeRegistry,
+    component_keys: Keys,
+    resource_keys: Keys,
+    saved: SavedSnapshots,
+    query: RollbackQuery,
+    last_saved_bytes: usize,
 }
 
 impl Snapshotter {
+    /// How many delta snapshots `save_to` saves after each keyframe.
+    pub const KEYFRAME_INTERVAL: usize = SavedSnapshots::KEYFRAME_INTERVAL;
+    /// How many snapshots are kept for rbrb to load.
+    pub const CAPACITY: usize = SavedSnapshots::CAPACITY;
+
     pub fn register_component<T: RegisterComponent>(&mut self) {
+        self.register_reflect_component::<T>(<ReflectTemplate as FromType<T>>::from_type());
+    }
+
+    /// Register a component that refers to other entities, so references to rollback entities
+    /// survive rollbacks that respawn them.
+    pub fn register_entity_references<T: RegisterMappedComponent>(&mut self) {
+        // Not every such component has a `Default`, e.g. `Parent`.
+        let prototype = T::from_world(&mut World::default());
+        self.register_reflect_component::<T>(ReflectTemplate::from_prototype(prototype));
+        let registration = self.component_registry.get_mut(TypeId::of::<T>()).unwrap();
+        registration.insert(<ReflectSnapshotEntities as FromType<T>>::from_type());
+    }
+
+    fn register_reflect_component<T: Component + GetTypeRegistration + Reflect + FromWorld>(
+        &mut self,
+        template: ReflectTemplate,
+    ) {
+        self.component_keys
+            .assign(TypeId::of::<T>(), std::any::type_name::<T>());
         self.component_registry.register::<T>();
-        let registration = self
-            .component_registry
-            .get_mut(std::any::TypeId::of::<T>())
-            .unwrap();
+        let registration = self.component_registry.get_mut(TypeId::of::<T>()).unwrap();
         registration.insert(<ReflectComponent as FromType<T>>::from_type());
+        registration.insert(template);
+        registration.insert(<ReflectChangeTicks as FromType<T>>::from_type());
+        self.query.invalidate();
+    }
+
+    /// Register a component that is encoded by `S` rather than through `Reflect`.
+    pub fn register_component_with<T: Component, S: RollbackCodec<T>>(&mut self) {
+        let key = self
+            .component_keys
+            .assign(TypeId::of::<T>(), std::any::type_name::<T>());
+        self.codec_components.retain(|(k, _)| *k != key);
+        self.codec_components
+            .push((key, CodecComponent::new::<T, S>()));
+    }
+
+    /// Register a component that is saved by cloning it, see [`Cloned`].
+    pub fn register_component_cloned<T: Component + Clone>(&mut self) {
+        log::warn!(
+            "{} is saved with Cloned, so checksums and sync tests do not cover it",
+            std::any::type_name::<T>()
+        );
+        self.register_cloned::<T>(ClonedComponent::new::<T>());
+    }
+
+    /// Register a component that is saved by cloning it, see [`ClonedEq`].
+    pub fn register_component_cloned_eq<T: Component + Clone + PartialEq>(&mut self) {
+        log::warn!(
+            "{} is saved with ClonedEq, so checksums do not cover it",
+            std::any::type_name::<T>()
+        );
+        self.register_cloned::<T>(ClonedComponent::new_eq::<T>());
+    }
+
+    fn register_cloned<T: Component>(&mut self, cloned: ClonedComponent) {
+        let key = self
+            .component_keys
+            .assign(TypeId::of::<T>(), std::any::type_name::<T>());
+        self.cloned_components.retain(|(k, _)| *k != key);
+        self.cloned_components.push((key, cloned));
     }
 
     pub fn register_resource<T: RegisterResource>(&mut self) {
+        self.resource_keys
+            .assign(TypeId::of::<T>(), std::any::type_name::<T>());
         self.resource_registry.register::<T>();
-        let registration = self
-            .resource_registry
-            .get_mut(std::any::TypeId::of::<T>())
-            .unwrap();
+        let registration = self.resource_registry.get_mut(TypeId::of::<T>()).unwrap();
         registration.insert(<ReflectResource as FromType<T>>::from_type());
+        registration.insert(<ReflectTemplate as FromType<T>>::from_type());
     }
 
+    /// Snapshots stay in memory, so only a handle to the snapshot is written to `vec`.
+    ///
+    /// Most snapshots are deltas that only store components changed since the last keyframe.
     pub fn save_to(&mut self, vec: &mut Vec<u8>, world: &mut World) {
+        let keyframe = self.saved.keyframe();
+        // Anything changed after this snapshot is taken gets a newer tick than `tick`.
+        let tick = world.increment_change_tick();
+        if let Some(snapshot) = self.snapshot_since(world, keyframe.map(|k| k.tick)) {
+            self.last_saved_bytes = snapshot.encoded_len();
+            let handle = self.saved.push(snapshot, keyframe, tick);
+            vec.extend_from_slice(&handle.to_le_bytes());
+        }
+        self.track_entities(world, &[]);
+    }
+
+    fn track_entities(&mut self, world: &mut World, restored: &[Entity]) {
+        let current = self
+            .query
+            .entities(world, &self.component_keys, &self.component_registry)
+            .into_iter()
+            .map(|e| (e.id, e.entity))
+            .collect();
+        crate::entities::track(world, current, restored);
+    }
+
+    /// The encoded size of the last snapshot saved by `save_to`. Components saved with the
+    /// [`Cloned`] strategy are not counted.
+    pub fn last_saved_bytes(&self) -> usize {
+        self.last_saved_bytes
+    }
+
+    /// Drops every saved snapshot, for when the session they were saved for has ended.
+    pub(crate) fn clear_saved(&mut self) {
+        self.saved = SavedSnapshots::default();
+        self.last_saved_bytes = 0;
+    }
+
+    pub fn checksum(&mut self, world: &mut World) -> Option<u64> {
+        let snapshot = self.snapshot(world)?;
+        let bytes = encoding()
+            .serialize(&snapshot)
+            .expect("snapshots are always serializable");
+        Some(crate::checksum::hash(&bytes))
+    }
+
+    pub(crate) fn snapshot(&mut self, world: &mut World) -> Option<Snapshot> {
+        self.snapshot_since(world, None)
+    }
+
+    /// Leaves out components that have not changed since `tick`, if given.
+    fn snapshot_since(&mut self, world: &mut World, tick: Option<u32>) -> Option<Snapshot> {
+        let entities = self
+            .query
+            .entities(world, &self.component_keys, &self.component_registry);
+        let mut errors = ErrorSink::new(world);
+        let snapshot = self.try_snapshot(world, &entities, tick, &mut errors).ok();
+        errors.report(world);
+        snapshot
+    }
+
+    fn try_snapshot(
+        &self,
+        world: &World,
+        entities: &[RollbackEntity],
+        tick: Option<u32>,
+        errors: &mut ErrorSink,
+    ) -> Result<Snapshot, Aborted> {
         let mut snapshot = Snapshot::default();
-        snapshot.fill_entities(world, &self.component_registry);
-        snapshot.fill_resources(world, &self.resource_registry);
-        bincode::serialize_into(vec, &snapshot).unwrap();
+        snapshot.fill_entities(world, self, entities, tick, errors)?;
+        snapshot.fill_resources(world, self, errors)?;
+        Ok(snapshot)
     }
 
     pub fn load_from(&mut self, slice: &[u8], world: &mut World) {
-        let mut snapshot: Snapshot = bincode::deserialize(slice).unwrap();
-        snapshot.apply_entities(world, &self.component_registry);
-        snapshot.apply_resources(world, &self.resource_registry);
+        let mut errors = ErrorSink::new(world);
+        let restored = match self.saved.get(slice) {
+            Ok(snapshot) => self
+                .try_load(snapshot, world, &mut errors)
+                .unwrap_or_default(),
+            Err(e) => {
+                errors.fatal(e);
+                Vec::new()
+            }
+        };
+        errors.report(world);
+        self.track_entities(world, &restored);
+    }
+
+    pub(crate) fn load(&mut self, snapshot: Snapshot, world: &mut World) {
+        let mut errors = ErrorSink::new(world);
+        let restored = self
+            .try_load(snapshot, world, &mut errors)
+            .unwrap_or_default();
+        errors.report(world);
+        self.track_entities(world, &restored);
+    }
+
+    /// Everything is decoded before anything is applied, so an aborted load leaves the world
+    /// untouched. Returns the entities that had to be spawned.
+    fn try_load(
+        &self,
+        snapshot: Snapshot,
+        world: &mut World,
+        errors: &mut ErrorSink,
+    ) -> Result<Vec<Entity>, Aborted> {
+        let decoded = snapshot.decode(world, self, errors)?;
+        Ok(decoded.apply(world, self, errors))
+    }
+
+    /// The first entity and component whose data differs between the two snapshots. The entity
+    /// is `None` for a resource.
+    pub(crate) fn diff(
+        &self,
+        ours: &Snapshot,
+        theirs: &Snapshot,
+    ) -> Option<(Option<RollbackId>, String)> {
+        let ids: BTreeSet<_> = ours.entities.keys().chain(theirs.entities.keys()).collect();
+        for id in ids {
+            let name = match (ours.entities.get(id), theirs.entities.get(id)) {
+                (Some(ours_components), Some(theirs_components)) => {
+                    diff_components(ours_components, theirs_components)
+                        .or_else(|| self.diff_cloned(ours.cloned.get(id), theirs.cloned.get(id)))
+                        .map(|key| self.component_keys.name(key))
+                }
+                _ => Some(std::any::type_name::<RollbackId>()),
+            };
+            if let Some(name) = name {
+                return Some((Some(*id), name.to_owned()));
+            }
+        }
+        diff_components(&ours.resources, &theirs.resources)
+            .map(|key| (None, self.resource_keys.name(key).to_owned()))
+    }
+
+    /// The first component saved with [`ClonedEq`] that differs. Components saved with [`Cloned`]
+    /// can only be told apart by whether they are present.
+    fn diff_cloned(
+        &self,
+        ours: Option<&BTreeMap<ComponentKey, Box<dyn ClonedValue>>>,
+        theirs: Option<&BTreeMap<ComponentKey, Box<dyn ClonedValue>>>,
+    ) -> Option<ComponentKey> {
+        self.cloned_components
+            .iter()
+            .find(|(key, cloned)| {
+                let ours = ours.and_then(|c| c.get(key));
+                let theirs = theirs.and_then(|c| c.get(key));
+                match (ours, theirs) {
+                    (Some(ours), Some(theirs)) => {
+                        cloned.values_eq(&**ours, &**theirs) == Some(false)
+                    }
+                    (ours, theirs) => ours.is_some() != theirs.is_some(),
+                }
+            })
+            .map(|(key, _)| *key)
     }
 }
 
 pub trait RegisterComponent: Component + GetTypeRegistration + Reflect + Default {}
 impl<T> RegisterComponent for T where T: Component + GetTypeRegistration + Reflect + Default {}
 
+/// A component that refers to other entities, e.g. `Parent` or `Children`.
+pub trait RegisterMappedComponent:
+    Component + GetTypeRegistration + Reflect + FromWorld + MapEntities + Clone
+{
+}
+impl<T> RegisterMappedComponent for T where
+    T: Component + GetTypeRegistration + Reflect + FromWorld + MapEntities + Clone
+{
+}
+
 pub trait RegisterResource: GetTypeRegistration + Reflect + Default {}
 impl<T> RegisterResource for T where T: GetTypeRegistration + Reflect + Default {}
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
-struct ComponentName(pub String);
+/// Identifies a registered component or resource in snapshots. Keys are assigned in registration
+/// order, so every peer must register the same rollback types in the same order.
+#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug)]
+struct ComponentKey(u16);
+
+#[derive(Default)]
+struct Keys {
+    by_type: HashMap<TypeId, ComponentKey>,
+    types: Vec<(TypeId, &'static str)>,
+}
+
+impl Keys {
+    fn assign(&mut self, type_id: TypeId, name: &'static str) -> ComponentKey {
+        let types = &mut self.types;
+        *self.by_type.entry(type_id).or_insert_with(|| {
+            types.push((type_id, name));
+            ComponentKey((types.len() - 1) as u16)
+        })
+    }
+
+    fn get(&self, type_id: TypeId) -> Option<ComponentKey> {
+        self.by_type.get(&type_id).copied()
+    }
+
+    fn type_id(&self, key: ComponentKey) -> Option<TypeId> {
+        self.types.get(key.0 as usize).map(|(type_id, _)| *type_id)
+    }
+
+    fn name(&self, key: ComponentKey) -> &'static str {
+        self.types
+            .get(key.0 as usize)
+            .map_or("<unregistered>", |(_, name)| *name)
+    }
+}
+
+/// Saved snapshots, oldest first, indexed by the handles given to rbrb.
+#[derive(Default)]
+struct SavedSnapshots {
+    snapshots: VecDeque<Saved>,
+    next_handle: u64,
+    keyframe: Option<Keyframe>,
+    since_keyframe: usize,
+}
+
+enum Saved {
+    Keyframe(Snapshot),
+    /// Only has the components that changed since `keyframe`.
+    Delta {
+        keyframe: u64,
+        delta: Snapshot,
+    },
+}
+
+#[derive(Clone, Copy)]
+struct Keyframe {
+    handle: u64,
+    /// The change tick the keyframe was taken at.
+    tick: u32,
+}
+
+impl SavedSnapshots {
+    const CAPACITY: usize = crate::ROLLBACK_WINDOW;
+    const KEYFRAME_INTERVAL: usize = 16;
+
+    /// The keyframe the next snapshot should be a delta against, or `None` if it should be a
+    /// keyframe itself.
+    fn keyframe(&self) -> Option<Keyframe> {
+        self.keyframe
+            .filter(|_| self.since_keyframe < Self::KEYFRAME_INTERVAL)
+    }
+
+    fn push(&mut self, snapshot: Snapshot, keyframe: Option<Keyframe>, tick: u32) -> u64 {
+        let handle = self.next_handle;
+        self.next_handle += 1;
+
+        let saved = match keyframe {
+            Some(keyframe) => {
+                self.since_keyframe += 1;
+                Saved::Delta {
+                    keyframe: keyframe.handle,
+                    delta: snapshot,
+                }
+            }
+            None => {
+                self.keyframe = Some(Keyframe { handle, tick });
+                self.since_keyframe = 0;
+                Saved::Keyframe(snapshot)
+            }
+        };
+        if self.snapshots.len() == Self::CAPACITY {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(saved);
+        handle
+    }
+
+    fn get(&self, handle: &[u8]) -> Result<Snapshot, SnapshotError> {
+        let handle = <[u8; 8]>::try_from(handle)
+            .map(u64::from_le_bytes)
+            .map_err(|_| SnapshotError::Decode(format!("{} byte snapshot handle", handle.len())))?;
+        match self.find(handle)? {
+            Saved::Keyframe(snapshot) => Ok(snapshot.clone()),
+            Saved::Delta { keyframe, delta } => match self.find(*keyframe)? {
+                Saved::Keyframe(keyframe) => delta.rebase(keyframe),
+                Saved::Delta { .. } => unreachable!("deltas are always against keyframes"),
+            },
+        }
+    }
+
+    fn find(&self, handle: u64) -> Result<&Saved, SnapshotError> {
+        let oldest = self.next_handle - self.snapshots.len() as u64;
+        handle
+            .checked_sub(oldest)
+            .and_then(|index| self.snapshots.get(index as usize))
+            .ok_or(SnapshotError::Expired(handle))
+    }
+}
+
+fn encoding() -> impl Options {
+    bincode::DefaultOptions::new()
+}
 
-#[derive(Default, Serialize, Deserialize, Debug)]
-struct Snapshot {
-    entities: BTreeMap<RollbackId, BTreeMap<ComponentName, Vec<u8>>>,
-    resources: BTreeMap<ComponentName, Vec<u8>>,
+#[derive(Default, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Snapshot {
+    entities: BTreeMap<RollbackId, BTreeMap<ComponentKey, Vec<u8>>>,
+    resources: BTreeMap<ComponentKey, Vec<u8>>,
+    /// Entity references in components are stored as [`map_entities::reference`]s into this
+    /// table.
+    references: Vec<RollbackId>,
+    /// Components saved with the [`Cloned`] strategy, which never leave memory.
+    #[serde(skip)]
+    cloned: BTreeMap<RollbackId, BTreeMap<ComponentKey, Box<dyn ClonedValue>>>,
+    /// Components left out of a delta snapshot because they are unchanged since its keyframe.
+    #[serde(skip)]
+    unchanged: BTreeMap<RollbackId, Vec<ComponentKey>>,
 }
 
 impl Snapshot {
-    fn fill_entities(&mut self, world: &World, registry: &TypeRegistry) {
+    fn fill_entities(
+        &mut self,
+        world: &World,
+        snapshotter: &Snapshotter,
+        rollback_entities: &[RollbackEntity],
+        since: Option<u32>,
+        errors: &mut ErrorSink,
+    ) -> Result<(), Aborted> {
+        let registry = &snapshotter.component_registry;
+        let world_registry = match world_registry(world) {
+            Ok(r) => r.read(),
+            Err(e) => return Err(errors.fatal(e)),
+        };
         self.entities.clear();
-        for arch in world.archetypes().iter() {
-            let entity_map: BTreeMap<_, _> = arch
-                .entities()
-                .iter()
-                .filter_map(|e| Some((*e, world.get::<RollbackId>(*e)?)))
-                .collect();
-
-            for component_id in arch.components() {
-                let component_info = match world.components().get_info(component_id) {
-                    Some(i) => i,
+
+        for rollback in rollback_entities {
+            self.entities.entry(rollback.id).or_default();
+        }
+        self.references = self.entities.keys().copied().collect();
+        let mut references = EntityMap::default();
+        for rollback in rollback_entities {
+            let index = self.references.binary_search(&rollback.id).unwrap();
+            references.insert(rollback.entity, map_entities::reference(index));
+        }
+
+        for rollback in rollback_entities {
+            let entity = rollback.entity;
+            for &(key, type_id) in snapshotter.query.columns(rollback.archetype) {
+                let registration = match registry.get(type_id) {
+                    Some(r) => r,
                     None => continue,
                 };
-                let component_name = component_info.name();
-                let reflect = match registry
-                    .get(component_info.type_id().unwrap())
-                    .and_then(|reg| reg.data::<ReflectComponent>())
-                {
+                let reflect = match registration.data::<ReflectComponent>() {
                     Some(r) => r,
                     None => continue,
                 };
-                for (entity, &rollback) in &entity_map {
-                    let component = match reflect.reflect_component(world, *entity) {
-                        Some(c) => c,
-                        None => continue,
-                    };
-                    let serialized = serialize_reflect(component, world);
-                    self.entities
-                        .entry(rollback.clone())
+                let mapper = registration.data::<ReflectSnapshotEntities>();
+                // Entity references are stored relative to each snapshot's own references, so
+                // they cannot be taken from the keyframe.
+                let change_ticks = match (since, mapper) {
+                    (Some(tick), None) => {
+                        registration.data::<ReflectChangeTicks>().map(|c| (c, tick))
+                    }
+                    _ => None,
+                };
+                if let Some((change_ticks, tick)) = change_ticks {
+                    if !change_ticks.changed_since(world, entity, tick) {
+                        self.unchanged.entry(rollback.id).or_default().push(key);
+                        continue;
+                    }
+                }
+                let component = match reflect.reflect_component(world, entity) {
+                    Some(c) => c,
+                    None => continue,
+                };
+                let mapped;
+                let component = match mapper.map(|m| m.map_entities(component, &mut references)) {
+                    None => component,
+                    Some(Ok(m)) => {
+                        mapped = m;
+                        &*mapped
+                    }
+                    Some(Err(e)) => {
+                        errors.handle(unmapped_entity(registration.name(), e))?;
+                        continue;
+                    }
+                };
+                let mut encoded = Vec::new();
+                if let Err(e) = reflect_codec::encode(component, &world_registry, &mut encoded) {
+                    errors.handle(e)?;
+                    continue;
+                }
+                self.entities
+                    .entry(rollback.id)
+                    .or_default()
+                    .insert(key, encoded);
+            }
+        }
+
+        for rollback in rollback_entities {
+            let entity = rollback.entity;
+            for (key, codec) in &snapshotter.codec_components {
+                if let Some(tick) = since {
+                    if codec.contains(world, entity) && !codec.changed_since(world, entity, tick) {
+                        self.unchanged.entry(rollback.id).or_default().push(*key);
+                        continue;
+                    }
+                }
+                let encoded = match codec.encode(world, entity) {
+                    Some(Ok(e)) => e,
+                    Some(Err(e)) => {
+                        errors.handle(e)?;
+                        continue;
+                    }
+                    None => continue,
+                };
+                self.entities
+                    .entry(rollback.id)
+                    .or_default()
+                    .insert(*key, encoded);
+            }
+            for (key, cloned) in &snapshotter.cloned_components {
+                if let Some(value) = cloned.get(world, entity) {
+                    self.cloned
+                        .entry(rollback.id)
                         .or_default()
-                        .insert(ComponentName(component_name.to_owned()), serialized);
+                        .insert(*key, value);
                 }
             }
         }
+        Ok(())
     }
 
-    fn fill_resources(&mut self, world: &World, registry: &TypeRegistry) {
+    fn fill_resources(
+        &mut self,
+        world: &World,
+        snapshotter: &Snapshotter,
+        errors: &mut ErrorSink,
+    ) -> Result<(), Aborted> {
+        let world_registry = match world_registry(world) {
+            Ok(r) => r.read(),
+            Err(e) => return Err(errors.fatal(e)),
+        };
         self.resources.clear();
         for component_id in world.archetypes().resource().unique_components().indices() {
             let component_info = match world.components().get_info(component_id) {
                 Some(i) => i,
                 None => continue,
             };
-            let component_name = component_info.name();
-            let reflect = match registry
-                .get(component_info.type_id().unwrap())
-                .and_then(|reg| reg.data::<ReflectResource>())
-            {
+            let (key, reflect) = match component_info.type_id().and_then(|type_id| {
+                Some((
+                    snapshotter.resource_keys.get(type_id)?,
+                    snapshotter
+                        .resource_registry
+                        .get(type_id)?
+                        .data::<ReflectResource>()?,
+                ))
+            }) {
                 Some(r) => r,
                 None => continue,
             };
@@ -124,84 +587,368 @@ impl Snapshot {
                 Some(r) => r,
                 None => continue,
             };
-            let serialized = serialize_reflect(resource, world);
-            self.resources
-                .insert(ComponentName(component_name.to_owned()), serialized);
+            let mut encoded = Vec::new();
+            if let Err(e) = reflect_codec::encode(resource, &world_registry, &mut encoded) {
+                errors.handle(e)?;
+                continue;
+            }
+            self.resources.insert(key, encoded);
         }
+        Ok(())
     }
 
-    fn apply_entities(&mut self, world: &mut World, registry: &TypeRegistry) {
+    fn encoded_len(&self) -> usize {
+        let components: usize = self
+            .entities
+            .values()
+            .flat_map(|components| components.values())
+            .map(Vec::len)
+            .sum();
+        let resources: usize = self.resources.values().map(Vec::len).sum();
+        components + resources
+    }
+
+    /// Fills in a delta snapshot's unchanged components from its keyframe.
+    fn rebase(&self, keyframe: &Snapshot) -> Result<Snapshot, SnapshotError> {
+        let mut snapshot = self.clone();
+        for (rollback, keys) in std::mem::take(&mut snapshot.unchanged) {
+            let saved = keyframe.entities.get(&rollback);
+            let components = snapshot.entities.entry(rollback).or_default();
+            for key in keys {
+                let data = saved.and_then(|c| c.get(&key)).ok_or_else(|| {
+                    SnapshotError::Decode(format!("{:?} is missing from its keyframe", rollback))
+                })?;
+                components.insert(key, data.clone());
+            }
+        }
+        Ok(snapshot)
+    }
+
+    fn decode(
+        mut self,
+        world: &World,
+        snapshotter: &Snapshotter,
+        errors: &mut ErrorSink,
+    ) -> Result<DecodedSnapshot, Aborted> {
+        let world_registry = match world_registry(world) {
+            Ok(r) => r.read(),
+            Err(e) => return Err(errors.fatal(e)),
+        };
+        let mut entities = BTreeMap::new();
+        for (rollback, mut components) in self.entities {
+            let codec =
+                decode_codec_components(&mut components, &snapshotter.codec_components, errors)?;
+            let reflect = decode_components(
+                components,
+                &snapshotter.component_keys,
+                &snapshotter.component_registry,
+                &world_registry,
+                errors,
+            )?;
+            let cloned = self.cloned.remove(&rollback).unwrap_or_default();
+            entities.insert(
+                rollback,
+                DecodedEntity {
+                    reflect,
+                    codec,
+                    cloned,
+                },
+            );
+        }
+        let resources = decode_components(
+            self.resources,
+            &snapshotter.resource_keys,
+            &snapshotter.resource_registry,
+            &world_registry,
+            errors,
+        )?;
+        Ok(DecodedSnapshot {
+            entities,
+            resources,
+            references: self.references,
+        })
+    }
+}
+
+struct DecodedSnapshot {
+    entities: BTreeMap<RollbackId, DecodedEntity>,
+    resources: HashMap<TypeId, Box<dyn Reflect>>,
+    references: Vec<RollbackId>,
+}
+
+struct DecodedEntity {
+    reflect: HashMap<TypeId, Box<dyn Reflect>>,
+    /// Kept with the encoded bytes, to compare against the current value when applying.
+    codec: BTreeMap<ComponentKey, (Vec<u8>, codec::Decoded)>,
+    cloned: BTreeMap<ComponentKey, Box<dyn ClonedValue>>,
+}
+
+impl DecodedSnapshot {
+    fn apply(
+        mut self,
+        world: &mut World,
+        snapshotter: &Snapshotter,
+        errors: &mut ErrorSink,
+    ) -> Vec<Entity> {
         let mut to_update = world.query::<(Entity, &RollbackId)>();
         let to_update = to_update
             .iter(world)
-            .map(|(entity, rollback)| (entity, rollback.clone()))
+            .map(|(entity, rollback)| (entity, *rollback))
             .collect::<Vec<_>>();
 
+        let mut updates = Vec::with_capacity(to_update.len());
+        let mut to_despawn = Vec::new();
         for (entity, rollback) in to_update {
-            let components = self.entities.remove(&rollback).expect(&format!(
-                "found entity with missing rollback data {}",
-                rollback.0
-            ));
-            self.apply_components_to(components, entity, registry, world);
+            match self.entities.remove(&rollback) {
+                Some(components) => updates.push((entity, rollback, components)),
+                None => to_despawn.push(entity),
+            }
         }
-        for (rollback, components) in std::mem::take(&mut self.entities) {
+
+        // Spawned after the snapshot was taken, so they should not exist yet.
+        for entity in to_despawn {
+            if world.get_entity(entity).is_some() {
+                despawn_with_children_recursive(world, entity);
+            }
+        }
+
+        let mut entities = BTreeMap::new();
+        for (entity, rollback, components) in updates {
+            if world.get_entity(entity).is_some() {
+                entities.insert(rollback, (entity, components));
+            } else {
+                // Was a descendant of a despawned entity.
+                self.entities.insert(rollback, components);
+            }
+        }
+        let mut spawned = Vec::with_capacity(self.entities.len());
+        for (rollback, components) in self.entities {
             let entity = world.spawn().insert(rollback).id();
-            self.apply_components_to(components, entity, registry, world);
+            entities.insert(rollback, (entity, components));
+            spawned.push(entity);
         }
+
+        // Every entity exists now, so references to them can be resolved.
+        let mut references = EntityMap::default();
+        for (index, rollback) in self.references.iter().enumerate() {
+            if let Some((entity, _)) = entities.get(rollback) {
+                references.insert(map_entities::reference(index), *entity);
+            }
+        }
+
+        for (entity, components) in entities.into_values() {
+            apply_components_to(
+                components.reflect,
+                entity,
+                &mut references,
+                &snapshotter.component_registry,
+                world,
+                errors,
+            );
+            apply_codec_components(
+                components.codec,
+                entity,
+                &snapshotter.codec_components,
+                world,
+            );
+            apply_cloned_components(
+                components.cloned,
+                entity,
+                &snapshotter.cloned_components,
+                world,
+            );
+        }
+        apply_resources(self.resources, world, &snapshotter.resource_registry);
+        spawned
     }
+}
 
-    fn apply_components_to(
-        &mut self,
-        mut components: BTreeMap<ComponentName, Vec<u8>>,
-        entity: Entity,
-        registry: &TypeRegistry,
-        world: &mut World,
-    ) {
-        for registration in registry.iter() {
-            let type_id = registration.type_id();
-            let reflect = registration.data::<ReflectComponent>().unwrap();
+fn decode_components(
+    components: BTreeMap<ComponentKey, Vec<u8>>,
+    keys: &Keys,
+    registry: &TypeRegistry,
+    world_registry: &TypeRegistry,
+    errors: &mut ErrorSink,
+) -> Result<HashMap<TypeId, Box<dyn Reflect>>, Aborted> {
+    let mut decoded = HashMap::new();
+    for (key, data) in components {
+        let registration = match keys.type_id(key).and_then(|type_id| registry.get(type_id)) {
+            Some(r) => r,
+            None => {
+                errors.handle(SnapshotError::UnknownComponent(format!("#{}", key.0)))?;
+                continue;
+            }
+        };
+        let template = registration
+            .data::<ReflectTemplate>()
+            .expect("registered with a template");
+        let mut value = template.default_value();
+        let mut input = &data[..];
+        let result = reflect_codec::decode(&mut *value, world_registry, &mut input);
+        let result = result.and_then(|()| match input.len() {
+            0 => Ok(()),
+            n => Err(SnapshotError::SchemaMismatch {
+                component: registration.name().to_owned(),
+                reason: format!("{} bytes left over", n),
+            }),
+        });
+        match result {
+            Ok(()) => {
+                decoded.insert(registration.type_id(), value);
+            }
+            Err(e) => errors.handle(e)?,
+        }
+    }
+    Ok(decoded)
+}
 
-            let component = components
-                .remove(&ComponentName(registration.name().to_string()))
-                .map(|data| deserialize_reflect(&data, world));
-            match (world.entity(entity).contains_type_id(type_id), component) {
-                (true, Some(c)) => reflect.apply_component(world, entity, &*c),
-                (false, Some(c)) => reflect.add_component(world, entity, &*c),
-                (true, None) => reflect.remove_component(world, entity),
-                (false, None) => {}
+fn decode_codec_components(
+    components: &mut BTreeMap<ComponentKey, Vec<u8>>,
+    codecs: &[(ComponentKey, CodecComponent)],
+    errors: &mut ErrorSink,
+) -> Result<BTreeMap<ComponentKey, (Vec<u8>, codec::Decoded)>, Aborted> {
+    let mut decoded = BTreeMap::new();
+    for (key, codec) in codecs {
+        let data = match components.remove(key) {
+            Some(d) => d,
+            None => continue,
+        };
+        match codec.decode(&data) {
+            Ok(value) => {
+                decoded.insert(*key, (data, value));
             }
+            Err(e) => errors.handle(e)?,
         }
     }
+    Ok(decoded)
+}
 
-    fn apply_resources(&mut self, world: &mut World, registry: &TypeRegistry) {
-        for registration in registry.iter() {
-            let reflect = registration.data::<ReflectResource>().unwrap();
+fn apply_components_to(
+    mut components: HashMap<TypeId, Box<dyn Reflect>>,
+    entity: Entity,
+    references: &mut EntityMap,
+    registry: &TypeRegistry,
+    world: &mut World,
+    errors: &mut ErrorSink,
+) {
+    for registration in registry.iter() {
+        let type_id = registration.type_id();
+        let reflect = registration.data::<ReflectComponent>().unwrap();
 
-            let resource = self
-                .resources
-                .remove(&ComponentName(registration.name().to_string()))
-                .map(|data| deserialize_reflect(&data, world));
-            match (reflect.reflect_resource(world), resource) {
-                (Some(_), Some(res)) => reflect.apply_resource(world, &*res),
-                (None, Some(res)) => reflect.add_resource(world, &*res),
-                (Some(_), None) => reflect.remove_resource(world),
-                (None, None) => {}
+        let component = components.remove(&type_id);
+        let component = match (component, registration.data::<ReflectSnapshotEntities>()) {
+            (Some(c), Some(mapper)) => match mapper.map_entities(&*c, references) {
+                Ok(mapped) => Some(mapped),
+                Err(e) => {
+                    // The world has already been partially updated, so it is too late to abort.
+                    let _ = errors.handle(unmapped_entity(registration.name(), e));
+                    continue;
+                }
+            },
+            (component, _) => component,
+        };
+        match (world.entity(entity).contains_type_id(type_id), component) {
+            (true, Some(c)) => {
+                // Writing an equal value would still trigger change detection.
+                let unchanged = reflect
+                    .reflect_component(world, entity)
+                    .and_then(|current| current.reflect_partial_eq(&*c))
+                    .unwrap_or(false);
+                if !unchanged {
+                    reflect.apply_component(world, entity, &*c);
+                }
+            }
+            (false, Some(c)) => reflect.add_component(world, entity, &*c),
+            (true, None) => reflect.remove_component(world, entity),
+            (false, None) => {}
+        }
+    }
+}
+
+fn apply_codec_components(
+    mut components: BTreeMap<ComponentKey, (Vec<u8>, codec::Decoded)>,
+    entity: Entity,
+    codecs: &[(ComponentKey, CodecComponent)],
+    world: &mut World,
+) {
+    for (key, codec) in codecs {
+        let component = components.remove(key);
+        match (codec.contains(world, entity), component) {
+            (true, Some((data, c))) => {
+                let unchanged =
+                    matches!(codec.encode(world, entity), Some(Ok(current)) if current == data);
+                if !unchanged {
+                    codec.insert(world, entity, c);
+                }
+            }
+            (false, Some((_, c))) => codec.insert(world, entity, c),
+            (true, None) => codec.remove(world, entity),
+            (false, None) => {}
+        }
+    }
+}
+
+fn apply_cloned_components(
+    mut components: BTreeMap<ComponentKey, Box<dyn ClonedValue>>,
+    entity: Entity,
+    cloned: &[(ComponentKey, ClonedComponent)],
+    world: &mut World,
+) {
+    for (key, cloned) in cloned {
+        let component = components.remove(key);
+        match (cloned.contains(world, entity), component) {
+            // Writing an equal value would still trigger change detection.
+            (true, Some(c)) if cloned.unchanged(world, entity, &*c) => {}
+            (_, Some(c)) => cloned.insert(world, entity, c),
+            (true, None) => cloned.remove(world, entity),
+            (false, None) => {}
+        }
+    }
+}
+
+fn unmapped_entity(component: &str, error: MapEntitiesError) -> SnapshotError {
+    match error {
+        MapEntitiesError::EntityNotFound(entity) => SnapshotError::UnmappedEntity {
+            component: component.to_owned(),
+            entity,
+        },
+    }
+}
+
+fn apply_resources(
+    mut resources: HashMap<TypeId, Box<dyn Reflect>>,
+    world: &mut World,
+    registry: &TypeRegistry,
+) {
+    for registration in registry.iter() {
+        let reflect = registration.data::<ReflectResource>().unwrap();
+
+        let resource = resources.remove(&registration.type_id());
+        match (reflect.reflect_resource(world), resource) {
+            (Some(current), Some(res)) => {
+                if current.reflect_partial_eq(&*res) != Some(true) {
+                    reflect.apply_resource(world, &*res);
+                }
             }
+            (None, Some(res)) => reflect.add_resource(world, &*res),
+            (Some(_), None) => reflect.remove_resource(world),
+            (None, None) => {}
         }
     }
 }
 
-fn serialize_reflect(reflect: &dyn Reflect, world: &World) -> Vec<u8> {
-    let world_registry = world.get_resource::<TypeRegistryArc>().unwrap().read();
-    let serializer = ReflectSerializer::new(reflect, &world_registry);
-    bson::to_vec(&serializer).unwrap()
+fn diff_components(
+    ours: &BTreeMap<ComponentKey, Vec<u8>>,
+    theirs: &BTreeMap<ComponentKey, Vec<u8>>,
+) -> Option<ComponentKey> {
+    let keys: BTreeSet<_> = ours.keys().chain(theirs.keys()).collect();
+    keys.into_iter()
+        .find(|key| ours.get(key) != theirs.get(key))
+        .copied()
 }
 
-fn deserialize_reflect(data: &[u8], world: &World) -> Box<dyn Reflect + 'static> {
-    let bson = bson::from_slice(data).unwrap();
-    let de = bson::Deserializer::new(bson);
-    let world_registry = world.get_resource::<TypeRegistryArc>().unwrap().read();
-    ReflectDeserializer::new(&world_registry)
-        .deserialize(de)
-        .unwrap()
+fn world_registry(world: &World) -> Result<&TypeRegistryArc, SnapshotError> {
+    world
+        .get_resource::<TypeRegistryArc>()
+        .ok_or(SnapshotError::MissingRegistry)
 }
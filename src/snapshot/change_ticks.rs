@@ -0,0 +1,34 @@
+use bevy_ecs::{component::Component, prelude::*};
+use bevy_reflect::FromType;
+
+/// Checks whether a component changed after a given change tick, so delta snapshots can skip it.
+#[derive(Clone)]
+pub(crate) struct ReflectChangeTicks {
+    changed_since: fn(&World, Entity, u32) -> bool,
+}
+
+impl ReflectChangeTicks {
+    pub(crate) fn changed_since(&self, world: &World, entity: Entity, tick: u32) -> bool {
+        (self.changed_since)(world, entity, tick)
+    }
+}
+
+impl<C: Component> FromType<C> for ReflectChangeTicks {
+    fn from_type() -> Self {
+        ReflectChangeTicks {
+            changed_since: changed_since::<C>,
+        }
+    }
+}
+
+/// Whether `entity`'s `C` was added or changed after `tick`. Returns `false` if it has no `C`.
+pub(crate) fn changed_since<C: Component>(world: &World, entity: Entity, tick: u32) -> bool {
+    let entity = match world.get_entity(entity) {
+        Some(e) => e,
+        None => return false,
+    };
+    // SAFETY: The `Mut` is only used to read the component's change ticks and is dropped before
+    // this returns, so it never aliases another reference to the component.
+    let component = unsafe { entity.get_unchecked_mut::<C>(tick, world.read_change_tick()) };
+    component.is_some_and(|c| c.is_changed())
+}
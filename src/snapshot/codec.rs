@@ -0,0 +1,97 @@
+use ::serde::{de::DeserializeOwned, Serialize};
+use bevy_ecs::{component::Component, prelude::*};
+use std::any::Any;
+
+use super::SnapshotError;
+
+pub type CodecError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Converts a rollback component to and from bytes, for types that cannot derive `Reflect`.
+pub trait RollbackCodec<T>: 'static {
+    fn encode(value: &T) -> Result<Vec<u8>, CodecError>;
+    fn decode(bytes: &[u8]) -> Result<T, CodecError>;
+}
+
+/// Encodes any serde type with bincode.
+pub struct BincodeCodec;
+
+impl<T: Serialize + DeserializeOwned> RollbackCodec<T> for BincodeCodec {
+    fn encode(value: &T) -> Result<Vec<u8>, CodecError> {
+        Ok(bincode::serialize(value)?)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<T, CodecError> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+pub(crate) type Decoded = Box<dyn Any + Send + Sync>;
+
+#[derive(Clone)]
+pub(crate) struct CodecComponent {
+    encode: fn(&World, Entity) -> Option<Result<Vec<u8>, SnapshotError>>,
+    decode: fn(&[u8]) -> Result<Decoded, SnapshotError>,
+    insert: fn(&mut World, Entity, Decoded),
+    remove: fn(&mut World, Entity),
+    contains: fn(&World, Entity) -> bool,
+    changed_since: fn(&World, Entity, u32) -> bool,
+}
+
+impl CodecComponent {
+    pub(crate) fn new<T: Component, S: RollbackCodec<T>>() -> Self {
+        CodecComponent {
+            encode: |world, entity| {
+                let component = world.get::<T>(entity)?;
+                Some(
+                    S::encode(component).map_err(|e| SnapshotError::SchemaMismatch {
+                        component: std::any::type_name::<T>().to_owned(),
+                        reason: e.to_string(),
+                    }),
+                )
+            },
+            decode: |bytes| match S::decode(bytes) {
+                Ok(c) => Ok(Box::new(c)),
+                Err(e) => Err(SnapshotError::Decode(e.to_string())),
+            },
+            insert: |world, entity, decoded| {
+                let component = *decoded
+                    .downcast::<T>()
+                    .expect("decoded by the same CodecComponent");
+                world.entity_mut(entity).insert(component);
+            },
+            remove: |world, entity| {
+                world.entity_mut(entity).remove::<T>();
+            },
+            contains: |world, entity| world.get::<T>(entity).is_some(),
+            changed_since: super::change_ticks::changed_since::<T>,
+        }
+    }
+
+    pub(crate) fn encode(
+        &self,
+        world: &World,
+        entity: Entity,
+    ) -> Option<Result<Vec<u8>, SnapshotError>> {
+        (self.encode)(world, entity)
+    }
+
+    pub(crate) fn decode(&self, bytes: &[u8]) -> Result<Decoded, SnapshotError> {
+        (self.decode)(bytes)
+    }
+
+    pub(crate) fn insert(&self, world: &mut World, entity: Entity, decoded: Decoded) {
+        (self.insert)(world, entity, decoded);
+    }
+
+    pub(crate) fn remove(&self, world: &mut World, entity: Entity) {
+        (self.remove)(world, entity);
+    }
+
+    pub(crate) fn contains(&self, world: &World, entity: Entity) -> bool {
+        (self.contains)(world, entity)
+    }
+
+    pub(crate) fn changed_since(&self, world: &World, entity: Entity, tick: u32) -> bool {
+        (self.changed_since)(world, entity, tick)
+    }
+}
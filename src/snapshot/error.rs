@@ -0,0 +1,103 @@
+use bevy_app::Events;
+use bevy_ecs::prelude::*;
+use derive_more::Display;
+
+#[derive(Display, Clone, Debug)]
+pub enum SnapshotError {
+    #[display(fmt = "world has no TypeRegistryArc resource")]
+    MissingRegistry,
+    #[display(fmt = "snapshot contains unregistered component {}", _0)]
+    UnknownComponent(String),
+    #[display(fmt = "could not decode snapshot: {}", _0)]
+    Decode(String),
+    #[display(fmt = "snapshot {} is no longer in memory", _0)]
+    Expired(u64),
+    #[display(fmt = "{} does not match its registration: {}", component, reason)]
+    SchemaMismatch { component: String, reason: String },
+    #[display(fmt = "{} refers to missing {:?}", component, entity)]
+    UnmappedEntity { component: String, entity: Entity },
+}
+
+impl std::error::Error for SnapshotError {}
+
+/// What to do when saving or loading a snapshot fails.
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SnapshotErrorPolicy {
+    #[default]
+    Panic,
+    /// Skip the offending entity or component and carry on with the rest of the snapshot.
+    Skip,
+    /// Leave the world untouched, end the session and send [`ResyncRequired`], so the app can
+    /// start a new one.
+    Resync,
+}
+
+/// Sent when a snapshot error under [`SnapshotErrorPolicy::Resync`] ended the session. The
+/// error is also sent as a [`SnapshotError`] event.
+#[derive(Clone, Debug)]
+pub struct ResyncRequired {
+    pub error: SnapshotError,
+}
+
+/// Inserted when a snapshot error under [`SnapshotErrorPolicy::Resync`] aborts a save or load,
+/// for the rbrb stage to end the session before rbrb is handed a missing snapshot.
+pub(crate) struct ResyncPending(pub(crate) SnapshotError);
+
+/// The snapshot could not be saved or loaded at all.
+pub(crate) struct Aborted;
+
+pub(crate) struct ErrorSink {
+    policy: SnapshotErrorPolicy,
+    errors: Vec<SnapshotError>,
+}
+
+impl ErrorSink {
+    pub(crate) fn new(world: &World) -> Self {
+        ErrorSink {
+            policy: world
+                .get_resource::<SnapshotErrorPolicy>()
+                .copied()
+                .unwrap_or_default(),
+            errors: Vec::new(),
+        }
+    }
+
+    /// Records an error that only affects part of the snapshot, returning `Err` if the whole
+    /// operation should be abandoned.
+    pub(crate) fn handle(&mut self, error: SnapshotError) -> Result<(), Aborted> {
+        match self.policy {
+            SnapshotErrorPolicy::Panic => panic!("{}", error),
+            SnapshotErrorPolicy::Skip => {
+                self.errors.push(error);
+                Ok(())
+            }
+            SnapshotErrorPolicy::Resync => {
+                self.errors.push(error);
+                Err(Aborted)
+            }
+        }
+    }
+
+    /// Records an error that makes the whole snapshot unusable.
+    pub(crate) fn fatal(&mut self, error: SnapshotError) -> Aborted {
+        match self.handle(error) {
+            Ok(()) | Err(Aborted) => Aborted,
+        }
+    }
+
+    pub(crate) fn report(self, world: &mut World) {
+        if self.errors.is_empty() {
+            return;
+        }
+        if self.policy == SnapshotErrorPolicy::Resync {
+            world.insert_resource(ResyncPending(self.errors[0].clone()));
+        }
+        let mut events = world.get_resource_mut::<Events<SnapshotError>>();
+        for error in self.errors {
+            log::error!("{}", error);
+            if let Some(events) = events.as_mut() {
+                events.send(error);
+            }
+        }
+    }
+}
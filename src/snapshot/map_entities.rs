@@ -0,0 +1,56 @@
+use bevy_ecs::entity::{Entity, EntityMap, MapEntities, MapEntitiesError};
+use bevy_reflect::{FromType, Reflect};
+
+/// Maps the entities a rollback component refers to, so snapshots can store them as
+/// `RollbackId`s instead of `Entity`s that may not exist after a rollback.
+#[derive(Clone)]
+pub(crate) struct ReflectSnapshotEntities {
+    map_entities: fn(&dyn Reflect, &EntityMap) -> Result<Box<dyn Reflect>, MapEntitiesError>,
+}
+
+impl ReflectSnapshotEntities {
+    /// References to entities that are not in `entity_map` and are not snapshot references, like
+    /// a child mesh without a `RollbackId`, are kept as they are.
+    pub(crate) fn map_entities(
+        &self,
+        component: &dyn Reflect,
+        entity_map: &mut EntityMap,
+    ) -> Result<Box<dyn Reflect>, MapEntitiesError> {
+        loop {
+            match (self.map_entities)(component, entity_map) {
+                Err(MapEntitiesError::EntityNotFound(entity)) if !is_reference(entity) => {
+                    entity_map.insert(entity, entity);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl<C: Reflect + MapEntities + Clone> FromType<C> for ReflectSnapshotEntities {
+    fn from_type() -> Self {
+        ReflectSnapshotEntities {
+            map_entities: |component, entity_map| {
+                let mut mapped = component
+                    .downcast_ref::<C>()
+                    .expect("snapshot components have their registered type")
+                    .clone();
+                mapped.map_entities(entity_map)?;
+                Ok(Box::new(mapped))
+            },
+        }
+    }
+}
+
+/// No live entity reaches this generation, so references can't be confused with entities that
+/// are stored as they are.
+const REFERENCE_GENERATION: u32 = u32::MAX;
+
+/// How a reference to the rollback entity at `index` in a snapshot's references is stored.
+pub(crate) fn reference(index: usize) -> Entity {
+    Entity::from_bits((REFERENCE_GENERATION as u64) << 32 | index as u64)
+}
+
+fn is_reference(entity: Entity) -> bool {
+    entity.generation() == REFERENCE_GENERATION
+}
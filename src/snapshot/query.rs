@@ -0,0 +1,73 @@
+use bevy_ecs::{archetype::ArchetypeId, prelude::*};
+use bevy_reflect::TypeRegistry;
+use std::{any::TypeId, collections::HashMap};
+
+use super::{ComponentKey, Keys};
+use crate::RollbackId;
+
+/// Finds rollback entities and their registered components without looking at the rest of the
+/// world.
+#[derive(Default)]
+pub(super) struct RollbackQuery {
+    query: Option<QueryState<(Entity, &'static RollbackId)>>,
+    /// The registered `Reflect` components of each archetype that has rollback entities.
+    /// Archetypes never change once created, so this only needs to be worked out once for each.
+    columns: HashMap<ArchetypeId, Vec<(ComponentKey, TypeId)>>,
+}
+
+pub(super) struct RollbackEntity {
+    pub(super) entity: Entity,
+    pub(super) id: RollbackId,
+    pub(super) archetype: ArchetypeId,
+}
+
+impl RollbackQuery {
+    pub(super) fn entities(
+        &mut self,
+        world: &mut World,
+        keys: &Keys,
+        registry: &TypeRegistry,
+    ) -> Vec<RollbackEntity> {
+        let query = self
+            .query
+            .get_or_insert_with(|| world.query::<(Entity, &RollbackId)>());
+        let world = &*world;
+
+        let mut entities = Vec::new();
+        for (entity, id) in query.iter(world) {
+            let archetype = world
+                .entities()
+                .get(entity)
+                .expect("queried entities exist")
+                .archetype_id;
+            self.columns.entry(archetype).or_insert_with(|| {
+                world
+                    .archetypes()
+                    .get(archetype)
+                    .into_iter()
+                    .flat_map(|a| a.components())
+                    .filter_map(|component_id| {
+                        let type_id = world.components().get_info(component_id)?.type_id()?;
+                        registry.get(type_id)?;
+                        Some((keys.get(type_id)?, type_id))
+                    })
+                    .collect()
+            });
+            entities.push(RollbackEntity {
+                entity,
+                id: *id,
+                archetype,
+            });
+        }
+        entities
+    }
+
+    pub(super) fn columns(&self, archetype: ArchetypeId) -> &[(ComponentKey, TypeId)] {
+        self.columns.get(&archetype).map_or(&[], |c| &c[..])
+    }
+
+    /// Forgets the columns, for when a component is registered.
+    pub(super) fn invalidate(&mut self) {
+        self.columns.clear();
+    }
+}
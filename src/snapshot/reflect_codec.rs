@@ -0,0 +1,211 @@
+//! A compact binary encoding for `Reflect` values.
+//!
+//! Unlike `ReflectSerializer`, no type or field names are written: values are decoded by walking
+//! a default instance of the registered type and filling in its fields in order. Lists and maps
+//! cannot be walked without an element to start from, so they fall back to BSON, with map entries
+//! sorted so equal values always encode to the same bytes.
+
+use ::serde::de::DeserializeSeed;
+use bevy_reflect::{
+    serde::{ReflectDeserializer, ReflectSerializer},
+    DynamicList, DynamicMap, DynamicStruct, DynamicTuple, DynamicTupleStruct, FromType, Reflect,
+    ReflectDeserialize, ReflectMut, ReflectRef, TypeRegistry,
+};
+use bincode::Options;
+use std::sync::Arc;
+
+use super::SnapshotError;
+
+/// Creates the default value of a registered type, to decode into.
+#[derive(Clone)]
+pub(crate) struct ReflectTemplate {
+    default: Arc<dyn Fn() -> Box<dyn Reflect> + Send + Sync>,
+}
+
+impl ReflectTemplate {
+    /// For types without a `Default`, which decode into a clone of `prototype` instead.
+    pub(crate) fn from_prototype<T: Reflect + Clone>(prototype: T) -> Self {
+        ReflectTemplate {
+            default: Arc::new(move || Box::new(prototype.clone())),
+        }
+    }
+
+    pub(crate) fn default_value(&self) -> Box<dyn Reflect> {
+        (self.default)()
+    }
+}
+
+impl<T: Reflect + Default> FromType<T> for ReflectTemplate {
+    fn from_type() -> Self {
+        ReflectTemplate {
+            default: Arc::new(|| Box::new(T::default())),
+        }
+    }
+}
+
+fn options() -> impl Options {
+    bincode::DefaultOptions::new()
+}
+
+pub(crate) fn encode(
+    value: &dyn Reflect,
+    registry: &TypeRegistry,
+    out: &mut Vec<u8>,
+) -> Result<(), SnapshotError> {
+    let mismatch = |reason: String| SnapshotError::SchemaMismatch {
+        component: value.type_name().to_owned(),
+        reason,
+    };
+
+    match value.reflect_ref() {
+        ReflectRef::Struct(s) => (0..s.field_len())
+            .filter_map(|i| s.field_at(i))
+            .try_for_each(|f| encode(f, registry, out)),
+        ReflectRef::TupleStruct(s) => (0..s.field_len())
+            .filter_map(|i| s.field(i))
+            .try_for_each(|f| encode(f, registry, out)),
+        ReflectRef::Tuple(t) => (0..t.field_len())
+            .filter_map(|i| t.field(i))
+            .try_for_each(|f| encode(f, registry, out)),
+        ReflectRef::List(_) | ReflectRef::Map(_) => {
+            let sorted = sorted(value, registry).map_err(mismatch)?;
+            let bson = bson::to_vec(&ReflectSerializer::new(&*sorted, registry))
+                .map_err(|e| mismatch(e.to_string()))?;
+            options()
+                .serialize_into(&mut *out, &bson)
+                .map_err(|e| mismatch(e.to_string()))
+        }
+        ReflectRef::Value(v) => {
+            let serializable = v.serializable().ok_or_else(|| {
+                mismatch("does not support ReflectValue serialization".to_owned())
+            })?;
+            options()
+                .serialize_into(&mut *out, serializable.borrow())
+                .map_err(|e| mismatch(e.to_string()))
+        }
+    }
+}
+
+/// A copy of `value` with the entries of every map in it sorted by their encoded key, as maps
+/// like `HashMap` otherwise serialize in an order that differs between peers.
+fn sorted(value: &dyn Reflect, registry: &TypeRegistry) -> Result<Box<dyn Reflect>, String> {
+    Ok(match value.reflect_ref() {
+        ReflectRef::Map(m) => {
+            let mut entries = Vec::with_capacity(m.len());
+            for (key, value) in m.iter() {
+                let encoded = bson::to_vec(&ReflectSerializer::new(key, registry))
+                    .map_err(|e| e.to_string())?;
+                entries.push((encoded, sorted(key, registry)?, sorted(value, registry)?));
+            }
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            let mut sorted = DynamicMap::default();
+            sorted.set_name(m.type_name().to_owned());
+            for (_, key, value) in entries {
+                sorted.insert_boxed(key, value);
+            }
+            Box::new(sorted)
+        }
+        ReflectRef::List(l) => {
+            let mut sorted = DynamicList::default();
+            sorted.set_name(l.type_name().to_owned());
+            for item in l.iter() {
+                sorted.push_box(self::sorted(item, registry)?);
+            }
+            Box::new(sorted)
+        }
+        ReflectRef::Struct(s) => {
+            let mut sorted = DynamicStruct::default();
+            sorted.set_name(s.type_name().to_owned());
+            for i in 0..s.field_len() {
+                if let (Some(name), Some(field)) = (s.name_at(i), s.field_at(i)) {
+                    sorted.insert_boxed(name, self::sorted(field, registry)?);
+                }
+            }
+            Box::new(sorted)
+        }
+        ReflectRef::TupleStruct(s) => {
+            let mut sorted = DynamicTupleStruct::default();
+            sorted.set_name(s.type_name().to_owned());
+            for field in s.iter_fields() {
+                sorted.insert_boxed(self::sorted(field, registry)?);
+            }
+            Box::new(sorted)
+        }
+        ReflectRef::Tuple(t) => {
+            let mut sorted = DynamicTuple::default();
+            for field in t.iter_fields() {
+                sorted.insert_boxed(self::sorted(field, registry)?);
+            }
+            Box::new(sorted)
+        }
+        ReflectRef::Value(v) => v.clone_value(),
+    })
+}
+
+/// Decodes into `value`, which should start out as the default for its type.
+pub(crate) fn decode(
+    value: &mut dyn Reflect,
+    registry: &TypeRegistry,
+    input: &mut &[u8],
+) -> Result<(), SnapshotError> {
+    let decode_error = |e: &dyn std::fmt::Display| SnapshotError::Decode(e.to_string());
+
+    if matches!(
+        value.reflect_ref(),
+        ReflectRef::List(_) | ReflectRef::Map(_)
+    ) {
+        let bson: Vec<u8> = options()
+            .deserialize_from(&mut *input)
+            .map_err(|e| decode_error(&e))?;
+        let document = bson::from_slice(&bson).map_err(|e| decode_error(&e))?;
+        let decoded = ReflectDeserializer::new(registry)
+            .deserialize(bson::Deserializer::new(document))
+            .map_err(|e| decode_error(&e))?;
+        value.apply(&*decoded);
+        return Ok(());
+    }
+
+    match value.reflect_mut() {
+        ReflectMut::Struct(s) => {
+            for i in 0..s.field_len() {
+                if let Some(field) = s.field_at_mut(i) {
+                    decode(field, registry, input)?;
+                }
+            }
+            Ok(())
+        }
+        ReflectMut::TupleStruct(s) => {
+            for i in 0..s.field_len() {
+                if let Some(field) = s.field_mut(i) {
+                    decode(field, registry, input)?;
+                }
+            }
+            Ok(())
+        }
+        ReflectMut::Tuple(t) => {
+            for i in 0..t.field_len() {
+                if let Some(field) = t.field_mut(i) {
+                    decode(field, registry, input)?;
+                }
+            }
+            Ok(())
+        }
+        ReflectMut::List(_) | ReflectMut::Map(_) => unreachable!("handled above"),
+        ReflectMut::Value(v) => {
+            let type_id = v.any().type_id();
+            let deserialize = registry
+                .get(type_id)
+                .and_then(|r| r.data::<ReflectDeserialize>())
+                .ok_or_else(|| SnapshotError::SchemaMismatch {
+                    component: v.type_name().to_owned(),
+                    reason: "not registered with ReflectDeserialize".to_owned(),
+                })?;
+            let mut de = bincode::Deserializer::with_reader(&mut *input, options());
+            let decoded = deserialize
+                .deserialize(&mut de)
+                .map_err(|e| decode_error(&e))?;
+            v.apply(&*decoded);
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use bevy_ecs::{component::Component, prelude::*};
+use std::{any::Any, fmt};
+
+use super::{RegisterComponent, Snapshotter};
+
+/// Chooses how a rollback component is saved, see
+/// [`add_rollback_component_as`](crate::RbrbAppExt::add_rollback_component_as).
+pub trait SnapshotStrategy<T> {
+    fn register(snapshotter: &mut Snapshotter);
+}
+
+/// Encodes the component through `Reflect`. This is what
+/// [`add_rollback_component`](crate::RbrbAppExt::add_rollback_component) uses.
+pub struct Reflected;
+
+impl<T: RegisterComponent> SnapshotStrategy<T> for Reflected {
+    fn register(snapshotter: &mut Snapshotter) {
+        snapshotter.register_component::<T>();
+    }
+}
+
+/// Keeps a clone of the component in memory instead of encoding it. This is the cheapest way to
+/// save a component, but cloned components are not covered by checksums or sync tests, and are
+/// always marked changed when a snapshot is loaded. Use [`ClonedEq`] for components that
+/// implement `PartialEq` to have them compared by sync tests and left untouched when unchanged.
+pub struct Cloned;
+
+impl<T: Component + Clone> SnapshotStrategy<T> for Cloned {
+    fn register(snapshotter: &mut Snapshotter) {
+        snapshotter.register_component_cloned::<T>();
+    }
+}
+
+/// Like [`Cloned`], but loading a snapshot leaves the component untouched if it is equal to the
+/// saved clone, so it is only marked changed when it actually differs. Sync tests compare these
+/// components too, though checksums still do not cover them.
+pub struct ClonedEq;
+
+impl<T: Component + Clone + PartialEq> SnapshotStrategy<T> for ClonedEq {
+    fn register(snapshotter: &mut Snapshotter) {
+        snapshotter.register_component_cloned_eq::<T>();
+    }
+}
+
+pub(crate) trait ClonedValue: Any + Send + Sync {
+    fn clone_boxed(&self) -> Box<dyn ClonedValue>;
+    fn as_any(&self) -> &dyn Any;
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
+}
+
+impl<T: Clone + Send + Sync + 'static> ClonedValue for T {
+    fn clone_boxed(&self) -> Box<dyn ClonedValue> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+impl Clone for Box<dyn ClonedValue> {
+    fn clone(&self) -> Self {
+        // `Box<dyn ClonedValue>` is itself a `ClonedValue`, so dispatch on the inner value.
+        (**self).clone_boxed()
+    }
+}
+
+impl fmt::Debug for dyn ClonedValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<cloned>")
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct ClonedComponent {
+    current: fn(&World, Entity) -> Option<&dyn ClonedValue>,
+    insert: fn(&mut World, Entity, Box<dyn ClonedValue>),
+    remove: fn(&mut World, Entity),
+    /// Only for components saved with [`ClonedEq`].
+    eq: Option<fn(&dyn ClonedValue, &dyn ClonedValue) -> bool>,
+}
+
+impl ClonedComponent {
+    pub(crate) fn new<T: Component + Clone>() -> Self {
+        ClonedComponent {
+            current: |world, entity| Some(world.get::<T>(entity)? as &dyn ClonedValue),
+            insert: |world, entity, value| {
+                let component = *value
+                    .into_any()
+                    .downcast::<T>()
+                    .expect("cloned by the same ClonedComponent");
+                world.entity_mut(entity).insert(component);
+            },
+            remove: |world, entity| {
+                world.entity_mut(entity).remove::<T>();
+            },
+            eq: None,
+        }
+    }
+
+    pub(crate) fn new_eq<T: Component + Clone + PartialEq>() -> Self {
+        ClonedComponent {
+            eq: Some(|a, b| a.as_any().downcast_ref::<T>() == b.as_any().downcast_ref::<T>()),
+            ..Self::new::<T>()
+        }
+    }
+
+    pub(crate) fn get(&self, world: &World, entity: Entity) -> Option<Box<dyn ClonedValue>> {
+        (self.current)(world, entity).map(|c| c.clone_boxed())
+    }
+
+    /// `None` for components without [`ClonedEq`], which can't be compared.
+    pub(crate) fn values_eq(&self, a: &dyn ClonedValue, b: &dyn ClonedValue) -> Option<bool> {
+        self.eq.map(|eq| eq(a, b))
+    }
+
+    /// Whether `entity` already has a component equal to `value`. Always `false` for components
+    /// without [`ClonedEq`].
+    pub(crate) fn unchanged(&self, world: &World, entity: Entity, value: &dyn ClonedValue) -> bool {
+        match ((self.current)(world, entity), self.eq) {
+            (Some(current), Some(eq)) => eq(current, value),
+            _ => false,
+        }
+    }
+
+    pub(crate) fn insert(&self, world: &mut World, entity: Entity, value: Box<dyn ClonedValue>) {
+        (self.insert)(world, entity, value);
+    }
+
+    pub(crate) fn remove(&self, world: &mut World, entity: Entity) {
+        (self.remove)(world, entity);
+    }
+
+    pub(crate) fn contains(&self, world: &World, entity: Entity) -> bool {
+        (self.current)(world, entity).is_some()
+    }
+}
@@ -1,13 +1,56 @@
+use bevy_app::Events;
 use bevy_ecs::{prelude::*, system::ExclusiveSystem};
 use rbrb::*;
 
-use std::ops::ControlFlow;
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashSet},
+    hash::{Hash, Hasher},
+    ops::ControlFlow,
+    time::Duration,
+};
+
+use crate::budget::{BudgetTracker, FrameBudget, FrameBudgetExceeded};
+use crate::session::{RbrbSessionEvent, RbrbState, SessionGeneration};
+use crate::snapshot::{ResyncPending, ResyncRequired};
+use crate::sync_test::{SyncTest, SyncTestFrame, SyncTestMismatch};
 
 pub struct RbrbStage {
     pub schedule: Schedule,
     pub get_inputs: Option<Box<dyn System<In = (), Out = Vec<u8>>>>,
     pub parse_inputs: Option<Box<dyn ExclusiveSystem>>,
     pub snapshotter: crate::snapshot::Snapshotter,
+    pub sync_test: Option<SyncTest>,
+    pub frame_budget: FrameBudget,
+    /// Run at the start of every simulated frame.
+    pub before_advance: Vec<fn(&mut World)>,
+    /// Run at the end of every simulated frame.
+    pub after_advance: Vec<fn(&mut World)>,
+    /// Called for each event added with `add_network_event` once a frame is confirmed.
+    pub confirm_network_events: Vec<fn(&mut World, u32)>,
+    /// Run when a session is started or ended at runtime, to forget the previous session.
+    pub reset_session: Vec<fn(&mut World)>,
+    pub request_handlers: Vec<Box<dyn RbrbRequestHandler>>,
+    /// Hashed discriminants of the unhandled request variants that have already been logged.
+    unhandled: HashSet<u64>,
+    status: RbrbState,
+    generation: u64,
+    /// The newest frame simulated so far, anything up to it is simulated again after a rollback.
+    newest_frame: Option<u32>,
+    /// The newest frame confirmed for every player.
+    newest_confirmed: Option<u32>,
+    /// Set from loading a snapshot until the re-simulation is back to `newest_frame`, so the
+    /// frame budget never leaves the world at a rolled back frame.
+    rolling_back: bool,
+    /// Counted from the update a rollback started in, if it took more than one.
+    resimulated_frames: u32,
+    /// Set while the budget keeps running out, so the warning is only logged once per streak.
+    over_budget: bool,
+}
+
+/// Handles rbrb requests that `RbrbPlugin` does not interpret itself.
+pub trait RbrbRequestHandler: Send + Sync + 'static {
+    /// Returns `request` back if it is not handled, so later handlers can try.
+    fn handle<'r>(&mut self, request: Request<'r>, world: &mut World) -> Option<Request<'r>>;
 }
 
 impl RbrbStage {
@@ -17,10 +60,25 @@ impl RbrbStage {
             get_inputs: None,
             parse_inputs: None,
             snapshotter: Default::default(),
+            sync_test: None,
+            frame_budget: FrameBudget::default(),
+            before_advance: Vec::new(),
+            after_advance: Vec::new(),
+            confirm_network_events: Vec::new(),
+            reset_session: Vec::new(),
+            request_handlers: Vec::new(),
+            unhandled: HashSet::new(),
+            status: RbrbState::NoSession,
+            generation: 0,
+            newest_frame: None,
+            newest_confirmed: None,
+            rolling_back: false,
+            resimulated_frames: 0,
+            over_budget: false,
         }
     }
 
-    fn handle_request(&mut self, request: Request, world: &mut World) {
+    fn handle_request(&mut self, request: Request, world: &mut World, budget: &mut BudgetTracker) {
         match request {
             Request::CaptureLocalInput(vec) => {
                 let inputs = self
@@ -37,41 +95,302 @@ impl RbrbStage {
                 current_frame,
                 ..
             } => {
-                world.insert_resource(inputs);
-                world.insert_resource(crate::RbrbTime { delta: amount });
-                world.insert_resource(confirmed);
-                world.insert_resource(crate::event::RbrbFrame(current_frame));
+                let first_confirmation = confirmed == Confirmation::First;
+                budget.advanced();
+
+                if self.newest_frame.is_some_and(|n| current_frame <= n) {
+                    self.resimulated_frames += 1;
+                } else {
+                    self.newest_frame = Some(current_frame);
+                }
+                if self.newest_frame == Some(current_frame) {
+                    self.rolling_back = false;
+                }
+                if first_confirmation {
+                    self.newest_confirmed = self.newest_confirmed.max(Some(current_frame));
+                }
+                if self.status != RbrbState::Running {
+                    self.set_status(RbrbState::Running, world);
+                    crate::session::send(world, RbrbSessionEvent::Running);
+                }
+
+                let before = match self.sync_test {
+                    Some(_) => self
+                        .snapshotter
+                        .snapshot(world)
+                        .map(|s| (s, inputs.clone(), confirmed.clone())),
+                    None => None,
+                };
 
-                if let Some(s) = self.parse_inputs.as_mut() {
-                    s.run(world);
+                self.advance(world, inputs, amount, confirmed, current_frame);
+
+                if first_confirmation {
+                    for confirm in &self.confirm_network_events {
+                        confirm(world, current_frame);
+                    }
+                    if let Some(checksum) = self.snapshotter.checksum(world) {
+                        let checksum = crate::checksum::FrameChecksum {
+                            frame: current_frame,
+                            checksum,
+                        };
+                        crate::checksum::record_local(world, checksum);
+                    }
                 }
-                self.schedule.run_once(world);
 
-                world.remove_resource::<crate::event::RbrbFrame>();
-                world.remove_resource::<rbrb::Confirmation>();
-                world.remove_resource::<crate::RbrbTime>();
-                world.remove_resource::<PlayerInputs>();
+                if let Some((before, inputs, confirmed)) = before {
+                    if let Some(after) = self.snapshotter.snapshot(world) {
+                        let frame = SyncTestFrame {
+                            frame: current_frame,
+                            inputs,
+                            amount,
+                            confirmed,
+                            before,
+                            after,
+                        };
+                        self.run_sync_test(frame, world);
+                    }
+                }
             }
 
             Request::SaveTo(vec) => self.snapshotter.save_to(vec, world),
-            Request::LoadFrom(slice) => self.snapshotter.load_from(slice, world),
+            Request::LoadFrom(slice) => {
+                self.rolling_back = true;
+                // The frames kept for checking belong to the timeline being rolled back.
+                if let Some(sync_test) = self.sync_test.as_mut() {
+                    sync_test.reset();
+                }
+                self.snapshotter.load_from(slice, world);
+            }
+
+            // Variants this plugin does not interpret go to `request_handlers`.
+            request => self.handle_other(request, world),
+        }
+    }
+
+    fn handle_other(&mut self, request: Request, world: &mut World) {
+        let mut request = request;
+        for handler in &mut self.request_handlers {
+            request = match handler.handle(request, world) {
+                Some(r) => r,
+                None => return,
+            };
+        }
+
+        let mut variant = DefaultHasher::new();
+        std::mem::discriminant(&request).hash(&mut variant);
+        if self.unhandled.insert(variant.finish()) {
+            log::warn!("ignoring unhandled rbrb request: {:?}", request);
+        }
+    }
+
+    fn advance(
+        &mut self,
+        world: &mut World,
+        inputs: PlayerInputs,
+        amount: Duration,
+        confirmed: Confirmation,
+        current_frame: u32,
+    ) {
+        world.insert_resource(inputs);
+        world.insert_resource(crate::RbrbTime { delta: amount });
+        world.insert_resource(confirmed);
+        world.insert_resource(crate::event::RbrbFrame(current_frame));
+        for before_advance in &self.before_advance {
+            before_advance(world);
+        }
+
+        if let Some(s) = self.parse_inputs.as_mut() {
+            s.run(world);
+        }
+        self.schedule.run_once(world);
+        for after_advance in &self.after_advance {
+            after_advance(world);
+        }
+
+        world.remove_resource::<crate::event::RbrbFrame>();
+        world.remove_resource::<rbrb::Confirmation>();
+        world.remove_resource::<crate::RbrbTime>();
+        world.remove_resource::<PlayerInputs>();
+    }
+
+    fn set_status(&mut self, status: RbrbState, world: &mut World) {
+        if self.status != status {
+            self.status = status;
+            world.insert_resource(status);
+        }
+    }
+
+    /// Forgets everything kept from the previous session. The `RollbackIdAllocator` is only
+    /// reset once no rollback entities are left, so kept entities never share ids with new ones.
+    fn reset(&mut self, world: &mut World) {
+        world.remove_resource::<crate::event::RbrbFrame>();
+        let current: BTreeMap<_, _> = world
+            .query::<(Entity, &crate::RollbackId)>()
+            .iter(world)
+            .map(|(entity, id)| (*id, entity))
+            .collect();
+        if current.is_empty() {
+            world.insert_resource(crate::RollbackIdAllocator::default());
+        }
+        crate::entities::track(world, current, &[]);
+        world.insert_resource(crate::RbrbChecksums::default());
+        world.insert_resource(crate::RbrbNetworkStats::default());
+
+        self.snapshotter.clear_saved();
+        if let Some(sync_test) = self.sync_test.as_mut() {
+            sync_test.reset();
+        }
+        self.unhandled.clear();
+        self.newest_frame = None;
+        self.newest_confirmed = None;
+        self.rolling_back = false;
+        self.resimulated_frames = 0;
+        self.over_budget = false;
+        self.set_status(RbrbState::NoSession, world);
+        for reset in &self.reset_session {
+            reset(world);
+        }
+    }
 
-            unhandled => {
-                unimplemented!("unhandled: {:?}", unhandled);
+    fn report_budget(&mut self, exceeded: Option<FrameBudgetExceeded>, world: &mut World) {
+        let exceeded = match exceeded {
+            Some(e) => e,
+            None => {
+                self.over_budget = false;
+                return;
             }
+        };
+        if !self.over_budget {
+            log::warn!(
+                "rbrb frame budget exceeded after {} advances in {:?}, deferring the rest",
+                exceeded.advances,
+                exceeded.elapsed
+            );
+        }
+        self.over_budget = true;
+        if let Some(mut events) = world.get_resource_mut::<Events<FrameBudgetExceeded>>() {
+            events.send(exceeded);
+        }
+    }
+
+    fn update_stats(&self, session: &Session, world: &mut World) {
+        let mut stats = match world.get_resource_mut::<crate::RbrbNetworkStats>() {
+            Some(s) => s,
+            None => return,
+        };
+        let players: Vec<usize> = session.players().map(|(id, _)| id as usize).collect();
+        stats.players.retain(|id, _| players.contains(id));
+        for id in players {
+            stats.players.entry(id).or_default();
         }
+        stats.prediction_depth = match (self.newest_frame, self.newest_confirmed) {
+            (Some(newest), Some(confirmed)) => newest.saturating_sub(confirmed),
+            (Some(newest), None) => newest + 1,
+            _ => 0,
+        };
+        stats.resimulated_frames = self.resimulated_frames;
+        stats.snapshot_bytes = self.snapshotter.last_saved_bytes();
+    }
+
+    /// Loads the state from `check_distance` frames ago, re-simulates up to the current frame and
+    /// checks that every re-simulated frame matches what was originally simulated.
+    fn run_sync_test(&mut self, frame: SyncTestFrame, world: &mut World) {
+        let mut sync_test = match self.sync_test.take() {
+            Some(t) => t,
+            None => return,
+        };
+        sync_test.push(frame);
+
+        if let Some(frames) = sync_test.frames_to_check() {
+            let first = frames.front().expect("checking at least one frame");
+            self.snapshotter.load(first.before.clone(), world);
+
+            world.insert_resource(crate::sync_test::Resimulating);
+            let mut mismatch = None;
+            for f in frames {
+                self.advance(
+                    world,
+                    f.inputs.clone(),
+                    f.amount,
+                    f.confirmed.clone(),
+                    f.frame,
+                );
+                if mismatch.is_some() {
+                    continue;
+                }
+                mismatch = self
+                    .snapshotter
+                    .snapshot(world)
+                    .and_then(|s| self.snapshotter.diff(&s, &f.after))
+                    .map(|(entity, component)| SyncTestMismatch {
+                        frame: f.frame,
+                        entity,
+                        component,
+                    });
+            }
+            world.remove_resource::<crate::sync_test::Resimulating>();
+
+            if let Some(mismatch) = mismatch {
+                let last = frames.back().expect("checking at least one frame");
+                self.snapshotter.load(last.after.clone(), world);
+                crate::sync_test::report(world, mismatch);
+            }
+            sync_test.finish_check();
+        }
+
+        self.sync_test = Some(sync_test);
     }
 }
 
 impl Stage for RbrbStage {
     fn run(&mut self, world: &mut World) {
+        let generation = world.get_resource::<SessionGeneration>().map_or(0, |g| g.0);
+        if generation != self.generation {
+            self.generation = generation;
+            self.reset(world);
+        }
+
         let mut session = match world.remove_resource::<Session>() {
             Some(s) => s,
-            None => return,
+            None => {
+                self.set_status(RbrbState::NoSession, world);
+                return;
+            }
         };
+        if self.status == RbrbState::NoSession {
+            self.set_status(RbrbState::Synchronizing, world);
+            crate::session::send(world, RbrbSessionEvent::Synchronizing { progress: 0.0 });
+        }
+        if !self.rolling_back {
+            self.resimulated_frames = 0;
+        }
+        let mut budget = BudgetTracker::new(self.frame_budget);
+        let mut exceeded = None;
         while let ControlFlow::Continue(()) = session.next_request(|request: Request<'_>| {
-            self.handle_request(request, world);
-        }) {}
+            self.handle_request(request, world, &mut budget);
+        }) {
+            if world.get_resource::<ResyncPending>().is_some() {
+                break;
+            }
+            if self.rolling_back {
+                continue;
+            }
+            exceeded = budget.exceeded();
+            if exceeded.is_some() {
+                break;
+            }
+        }
+        if let Some(ResyncPending(error)) = world.remove_resource::<ResyncPending>() {
+            log::error!("ending the rbrb session to resync after: {}", error);
+            crate::session::next_generation(world);
+            self.set_status(RbrbState::NoSession, world);
+            if let Some(mut events) = world.get_resource_mut::<Events<ResyncRequired>>() {
+                events.send(ResyncRequired { error });
+            }
+            return;
+        }
+        self.report_budget(exceeded, world);
+        self.update_stats(&session, world);
         world.insert_resource(session);
     }
 }
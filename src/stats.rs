@@ -0,0 +1,27 @@
+use std::{collections::BTreeMap, time::Duration};
+
+/// Network and rollback statistics, updated every time the rbrb stage runs.
+#[derive(Default, Debug)]
+pub struct RbrbNetworkStats {
+    /// Keyed by rbrb player id. Every player in the session has an entry.
+    pub players: BTreeMap<usize, PlayerNetworkStats>,
+    /// How many frames past the newest frame confirmed for every player the local simulation
+    /// has predicted.
+    pub prediction_depth: u32,
+    /// Frames simulated again after loading a snapshot during the last update. New frames
+    /// simulated after catching up are not counted.
+    pub resimulated_frames: u32,
+    /// The encoded size of the last saved snapshot, see [`Snapshotter::last_saved_bytes`].
+    ///
+    /// [`Snapshotter::last_saved_bytes`]: crate::Snapshotter::last_saved_bytes
+    pub snapshot_bytes: usize,
+}
+
+/// rbrb does not report these itself, so the plugin leaves them `None`. App code that can measure
+/// them, e.g. from its own socket, can set them and they are kept while the player is in the
+/// session.
+#[derive(Default, Debug, Clone)]
+pub struct PlayerNetworkStats {
+    pub round_trip_time: Option<Duration>,
+    pub packets_lost: Option<u64>,
+}
@@ -0,0 +1,78 @@
+use bevy_app::Events;
+use bevy_ecs::prelude::*;
+use rbrb::{Confirmation, PlayerInputs};
+use std::{collections::VecDeque, time::Duration};
+
+use crate::{snapshot::Snapshot, RollbackId};
+
+/// Reported when re-simulating a frame from a loaded snapshot produced different state than the
+/// original simulation of that frame.
+#[derive(Clone, Debug)]
+pub struct SyncTestMismatch {
+    pub frame: u32,
+    /// The first entity that differs, or `None` if only a resource differs.
+    pub entity: Option<RollbackId>,
+    pub component: String,
+}
+
+pub struct SyncTest {
+    check_distance: usize,
+    history: VecDeque<SyncTestFrame>,
+}
+
+pub struct SyncTestFrame {
+    pub frame: u32,
+    pub inputs: PlayerInputs,
+    pub amount: Duration,
+    pub confirmed: Confirmation,
+    pub(crate) before: Snapshot,
+    pub(crate) after: Snapshot,
+}
+
+impl SyncTest {
+    pub fn new(check_distance: usize) -> Self {
+        SyncTest {
+            check_distance: check_distance.max(1),
+            history: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, frame: SyncTestFrame) {
+        self.history.push_back(frame);
+    }
+
+    /// The frames to re-simulate, once enough frames have been recorded.
+    pub fn frames_to_check(&self) -> Option<&VecDeque<SyncTestFrame>> {
+        if self.history.len() < self.check_distance {
+            return None;
+        }
+        Some(&self.history)
+    }
+
+    pub fn finish_check(&mut self) {
+        self.history.pop_front();
+    }
+
+    pub fn reset(&mut self) {
+        self.history.clear();
+    }
+}
+
+/// Present while the sync test re-simulates frames that have already been simulated, and sent
+/// their network events, once.
+pub struct Resimulating;
+
+pub(crate) fn report(world: &mut World, mismatch: SyncTestMismatch) {
+    log::error!(
+        "sync test failed at frame {}: {} differs on {}",
+        mismatch.frame,
+        mismatch.component,
+        match &mismatch.entity {
+            Some(e) => format!("entity {:?}", e),
+            None => "resources".to_string(),
+        }
+    );
+    if let Some(mut events) = world.get_resource_mut::<Events<SyncTestMismatch>>() {
+        events.send(mismatch);
+    }
+}